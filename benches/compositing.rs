@@ -0,0 +1,124 @@
+// Times compositing a 480x320 frame the old way, one float blend per pixel, against the
+// row-based blending in src/virtual/blend.rs. Run with `cargo bench`.
+
+#[path = "../src/virtual/blend.rs"]
+mod blend;
+
+use blend::{Source, composite};
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+const WIDTH: u32 = 480;
+const HEIGHT: u32 = 320;
+const FRAMES: u32 = 100;
+
+/// An RGBA layer filled by `pixel(x, y)`.
+fn layer(width: u32, height: u32, pixel: impl Fn(u32, u32) -> [u8; 4]) -> Vec<u8> {
+    let mut data = Vec::with_capacity((width * height * 4) as usize);
+    for y in 0..height {
+        for x in 0..width {
+            data.extend_from_slice(&pixel(x, y));
+        }
+    }
+    data
+}
+
+/// The per-pixel float blend compositing used to do.
+fn composite_per_pixel(buffer: &mut [u8], width: u32, sources: &[Source]) {
+    buffer.fill(0);
+    for source in sources {
+        let (lx, ly, lw, lh) = source.bounds;
+        for row in 0..lh {
+            for col in 0..lw {
+                let (x, y) = (lx + col, ly + row);
+                if x >= width || y >= HEIGHT {
+                    continue;
+                }
+                let s = &source.data[((row * lw + col) * 4) as usize..][..4];
+                let idx = ((y * width + x) * 4) as usize;
+                let dst = &mut buffer[idx..idx + 4];
+                let src_a = s[3] as f32 / 255.0;
+                let inv_a = 1.0 - src_a;
+                dst[0] = (s[0] as f32 * src_a + dst[0] as f32 * inv_a) as u8;
+                dst[1] = (s[1] as f32 * src_a + dst[1] as f32 * inv_a) as u8;
+                dst[2] = (s[2] as f32 * src_a + dst[2] as f32 * inv_a) as u8;
+                dst[3] = 255;
+            }
+        }
+    }
+}
+
+fn time(name: &str, baseline: Option<Duration>, mut frame: impl FnMut()) -> Duration {
+    frame();
+    let start = Instant::now();
+    for _ in 0..FRAMES {
+        frame();
+    }
+    let elapsed = start.elapsed() / FRAMES;
+
+    match baseline {
+        Some(baseline) => println!(
+            "{:<28} {:>8.3} ms/frame  {:>5.1}x",
+            name,
+            elapsed.as_secs_f64() * 1000.0,
+            baseline.as_secs_f64() / elapsed.as_secs_f64()
+        ),
+        None => println!(
+            "{:<28} {:>8.3} ms/frame",
+            name,
+            elapsed.as_secs_f64() * 1000.0
+        ),
+    }
+    elapsed
+}
+
+fn main() {
+    // An opaque background, a translucent panel and sparse text-like content on top
+    let background = layer(WIDTH, HEIGHT, |x, y| {
+        [(x / 2) as u8, (y / 2) as u8, 96, 255]
+    });
+    let panel = layer(300, 200, |_, _| [20, 20, 20, 160]);
+    let text = layer(WIDTH, 60, |x, y| {
+        if (x / 3 + y / 4) % 5 == 0 {
+            [255, 255, 255, 255]
+        } else if (x + y) % 7 == 0 {
+            [255, 255, 255, 96]
+        } else {
+            [0, 0, 0, 0]
+        }
+    });
+
+    let sources = [
+        Source {
+            bounds: (0, 0, WIDTH, HEIGHT),
+            data: &background,
+        },
+        Source {
+            bounds: (90, 60, 300, 200),
+            data: &panel,
+        },
+        Source {
+            bounds: (0, 240, WIDTH, 60),
+            data: &text,
+        },
+    ];
+    let mut buffer = vec![0u8; (WIDTH * HEIGHT * 4) as usize];
+    let rect = (0, 0, WIDTH, HEIGHT);
+
+    let baseline = time("per-pixel float", None, || {
+        composite_per_pixel(black_box(&mut buffer), WIDTH, &sources);
+    });
+
+    let cores = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let mut thread_counts = vec![1, 2, 4, cores];
+    thread_counts.sort();
+    thread_counts.dedup();
+
+    for threads in thread_counts {
+        time(
+            &format!("rows, {} thread(s)", threads),
+            Some(baseline),
+            || composite(black_box(&mut buffer), WIDTH, rect, &sources, threads),
+        );
+    }
+}
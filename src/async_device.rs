@@ -0,0 +1,142 @@
+use crate::device::{Device, DeviceError, Orientation, StreamStats, settle};
+use crate::protocol::DeviceInfo;
+use std::panic;
+use std::sync::{Arc, Mutex};
+use tokio::task;
+
+/// An async front end to a `Device`.
+///
+/// Every command runs on tokio's blocking thread pool, so serial writes never stall the
+/// runtime. Commands awaited one after another reach the panel in order. The device is
+/// shared, so a `Display` drives the same panel at the same time.
+#[derive(Clone)]
+pub struct AsyncDevice {
+    device: Arc<Mutex<Device>>,
+}
+
+impl AsyncDevice {
+    pub fn from_shared(device: Arc<Mutex<Device>>) -> Self {
+        AsyncDevice { device }
+    }
+
+    /// Runs a command against the device on the blocking thread pool. If the link fails,
+    /// or the command leaves a paced upload going, this waits until the device has
+    /// nothing left to do, with the device unlocked in between.
+    pub async fn run<F>(&self, command: F) -> Result<(), DeviceError>
+    where
+        F: FnOnce(&mut Device) -> Result<(), DeviceError> + Send + 'static,
+    {
+        let device = self.device.clone();
+        run_blocking(move || {
+            let result = command(&mut device.lock().unwrap());
+            match result {
+                Ok(()) | Err(DeviceError::Recovering { .. }) => settle(&device),
+                Err(err) => Err(err),
+            }
+        })
+        .await
+    }
+
+    pub async fn info(&self) -> DeviceInfo {
+        let device = self.device.clone();
+        run_blocking(move || device.lock().unwrap().info().clone()).await
+    }
+
+    /// Totals for every bitmap streamed so far.
+    pub async fn stream_stats(&self) -> StreamStats {
+        let device = self.device.clone();
+        run_blocking(move || device.lock().unwrap().stream_stats()).await
+    }
+
+    pub async fn screen_on(&self) -> Result<(), DeviceError> {
+        self.run(|dev| dev.screen_on()).await
+    }
+
+    pub async fn screen_off(&self) -> Result<(), DeviceError> {
+        self.run(|dev| dev.screen_off()).await
+    }
+
+    pub async fn set_brightness(&self, brightness: u8) -> Result<(), DeviceError> {
+        self.run(move |dev| dev.set_brightness(brightness)).await
+    }
+
+    pub async fn set_orientation(&self, orientation: Orientation) -> Result<(), DeviceError> {
+        self.run(move |dev| dev.set_orientation(orientation)).await
+    }
+
+    pub async fn display_picture(
+        &self,
+        image_data: Vec<u8>,
+        x: u16,
+        y: u16,
+        width: u16,
+        height: u16,
+    ) -> Result<(), DeviceError> {
+        self.run(move |dev| dev.display_picture(image_data, x, y, width, height))
+            .await
+    }
+
+    /// Restarts the panel and waits until it is back with its state restored.
+    pub async fn reset(&self) -> Result<(), DeviceError> {
+        self.run(|dev| dev.reset()).await
+    }
+}
+
+/// Runs blocking work on the blocking thread pool, passing on any panic.
+async fn run_blocking<T, F>(work: F) -> T
+where
+    T: Send + 'static,
+    F: FnOnce() -> T + Send + 'static,
+{
+    match task::spawn_blocking(work).await {
+        Ok(result) => result,
+        Err(err) => panic::resume_unwind(err.into_panic()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::StreamConfig;
+    use crate::protocol::Protocol;
+    use crate::protocol::rev_a::RevA;
+    use crate::transport::MemoryTransport;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn commands_reach_the_panel_in_order() {
+        let memory = MemoryTransport::new();
+        let mut device =
+            Device::with_protocol(Box::new(memory.clone()), Box::new(RevA::new()), 4, 4).unwrap();
+        // Paced, so only waiting for the upload to settle gets every chunk out
+        device.set_stream_config(StreamConfig {
+            rows_per_chunk: 1,
+            chunk_timeout: None,
+            chunk_delay: Some(Duration::from_millis(1)),
+        });
+        memory.take_written();
+        let device = AsyncDevice::from_shared(Arc::new(Mutex::new(device)));
+
+        device.set_brightness(50).await.unwrap();
+        device
+            .set_orientation(Orientation::Landscape)
+            .await
+            .unwrap();
+        device
+            .display_picture(vec![7; 2 * 2 * 2], 1, 1, 2, 2)
+            .await
+            .unwrap();
+        device.screen_off().await.unwrap();
+        device.screen_on().await.unwrap();
+
+        let mut rev_a = RevA::new();
+        let mut expected = rev_a.brightness(50).unwrap();
+        expected.extend(rev_a.orientation(Orientation::Landscape, 4, 4));
+        expected.extend(rev_a.display_image(1, 1, 2, 2).unwrap());
+        expected.extend([7; 2 * 2 * 2]);
+        expected.extend(rev_a.screen_off());
+        expected.extend(rev_a.screen_on());
+        assert_eq!(memory.written(), expected);
+        assert_eq!(device.stream_stats().await.chunks, 2);
+    }
+}
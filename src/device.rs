@@ -1,32 +1,131 @@
-use crate::image_extensions::load_png_to_rgb565;
-use crate::packets::{
-    create_display_image_packet, create_hello_packet, create_orientation_packet,
-    create_screen_black_packet, create_screen_brightness_packet, create_screen_off_packet,
-    create_screen_on_packet, create_screen_white_packet,
-};
-use serialport::SerialPort;
+use crate::discovery::{UsbId, describe_ports, find_port};
+use crate::dither::Dither;
+use crate::image_extensions::{Scaling, load_png_to_rgb565};
+use crate::packets::{EncodeError, check_payload, region_end};
+use crate::protocol::rev_a::RevA;
+use crate::protocol::rev_b::RevB;
+use crate::protocol::rev_c::RevC;
+use crate::protocol::{DeviceInfo, PROBE_TIMEOUT, Protocol, Revision, detect, read_reply};
+use crate::trace::RecordingTransport;
+use crate::transport::{FileTransport, SerialTransport, TcpTransport, Transport};
 use std::fmt;
-use std::time::Duration;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
 
 #[derive(Debug)]
 pub enum DeviceError {
     IoError(std::io::Error),
-    PacketError(String),
     ImageError(String),
+    EncodeError(EncodeError),
+    PortNotFound {
+        wanted: String,
+        candidates: Vec<String>,
+    },
+    /// The port exists but this user may not open it.
+    PermissionDenied {
+        port: String,
+    },
+    /// The panel stopped answering or taking data.
+    Timeout(std::io::Error),
+    /// The port went away, usually because the panel was unplugged or restarted.
+    Disconnected(std::io::Error),
+    InvalidArgument(String),
+    /// The panel doesn't speak the protocol it was expected to.
+    ProtocolMismatch(String),
+    /// The link failed and the device is bringing it back, which `Device::poll` moves
+    /// along. The command's effect is replayed once the link is back.
+    Recovering {
+        cause: String,
+        recovery: Recovery,
+    },
+}
+
+impl DeviceError {
+    /// Sorts an I/O error into timeouts, disconnects and everything else.
+    pub fn from_io(err: std::io::Error) -> Self {
+        use std::io::ErrorKind;
+
+        match err.kind() {
+            ErrorKind::TimedOut | ErrorKind::WouldBlock => DeviceError::Timeout(err),
+            ErrorKind::BrokenPipe
+            | ErrorKind::NotConnected
+            | ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionAborted
+            | ErrorKind::UnexpectedEof => DeviceError::Disconnected(err),
+            _ => DeviceError::IoError(err),
+        }
+    }
+
+    /// Whether trying again, after reconnecting if need be, might succeed. Anything else
+    /// won't go away until the caller changes something.
+    pub fn is_recoverable(&self) -> bool {
+        match self {
+            DeviceError::IoError(_)
+            | DeviceError::PortNotFound { .. }
+            | DeviceError::Timeout(_)
+            | DeviceError::Disconnected(_)
+            | DeviceError::Recovering { .. } => true,
+            DeviceError::ImageError(_)
+            | DeviceError::EncodeError(_)
+            | DeviceError::PermissionDenied { .. }
+            | DeviceError::InvalidArgument(_)
+            | DeviceError::ProtocolMismatch(_) => false,
+        }
+    }
+
+    /// Classifies a failure to open a serial port.
+    fn from_open(port_name: &str, err: serialport::Error) -> Self {
+        use std::io::ErrorKind;
+
+        match err.kind() {
+            serialport::ErrorKind::NoDevice | serialport::ErrorKind::Io(ErrorKind::NotFound) => {
+                DeviceError::PortNotFound {
+                    wanted: port_name.to_string(),
+                    candidates: describe_ports(),
+                }
+            }
+            serialport::ErrorKind::Io(ErrorKind::PermissionDenied) => {
+                DeviceError::PermissionDenied {
+                    port: port_name.to_string(),
+                }
+            }
+            serialport::ErrorKind::InvalidInput => DeviceError::InvalidArgument(err.to_string()),
+            _ => DeviceError::from_io(err.into()),
+        }
+    }
 }
 
 impl fmt::Display for DeviceError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             DeviceError::IoError(e) => write!(f, "I/O error: {}", e),
-            DeviceError::PacketError(msg) => write!(f, "Packet error: {}", msg),
             DeviceError::ImageError(msg) => write!(f, "Image error: {}", msg),
+            DeviceError::EncodeError(e) => write!(f, "Encode error: {}", e),
+            DeviceError::PortNotFound { wanted, candidates } => {
+                write!(f, "No serial port matched {}", wanted)?;
+                if candidates.is_empty() {
+                    write!(f, " (no serial ports found)")
+                } else {
+                    write!(f, "; candidates: {}", candidates.join(", "))
+                }
+            }
+            DeviceError::PermissionDenied { port } => {
+                write!(f, "Permission denied opening {}", port)
+            }
+            DeviceError::Timeout(e) => write!(f, "Timed out: {}", e),
+            DeviceError::Disconnected(e) => write!(f, "Disconnected: {}", e),
+            DeviceError::InvalidArgument(msg) => write!(f, "Invalid argument: {}", msg),
+            DeviceError::ProtocolMismatch(msg) => write!(f, "Protocol mismatch: {}", msg),
+            DeviceError::Recovering { cause, recovery } => write!(f, "{}; {}", cause, recovery),
         }
     }
 }
 
 impl std::error::Error for DeviceError {}
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Orientation {
     Portrait,
     Landscape,
@@ -43,6 +142,324 @@ impl Orientation {
             Orientation::ReverseLandscape => 3,
         }
     }
+
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(Orientation::Portrait),
+            1 => Some(Orientation::ReversePortrait),
+            2 => Some(Orientation::Landscape),
+            3 => Some(Orientation::ReverseLandscape),
+            _ => None,
+        }
+    }
+}
+
+/// Where to find the display's serial port.
+#[derive(Debug, Clone)]
+pub enum PortSelector {
+    /// An explicit port path such as `/dev/ttyACM0` or `COM4`.
+    Path(String),
+    /// Search the available ports for a matching USB device.
+    Usb(UsbId),
+    /// A `host:port` bridge that forwards the protocol to a panel elsewhere.
+    Tcp(String),
+    /// Write the packet stream to a file instead of a panel.
+    File(PathBuf),
+}
+
+impl PortSelector {
+    /// Parses `usb:vid:pid[:serial]` (vid and pid in hex), `tcp:host:port` and
+    /// `file:path`; anything else is a serial port path.
+    pub fn parse(port: &str) -> Self {
+        if let Some(id) = port.strip_prefix("usb:").and_then(parse_usb_id) {
+            PortSelector::Usb(id)
+        } else if let Some(addr) = port.strip_prefix("tcp:") {
+            PortSelector::Tcp(addr.to_string())
+        } else if let Some(path) = port.strip_prefix("file:") {
+            PortSelector::File(path.into())
+        } else {
+            PortSelector::Path(port.to_string())
+        }
+    }
+}
+
+fn parse_usb_id(id: &str) -> Option<UsbId> {
+    let mut parts = id.splitn(3, ':');
+    let vid = u16::from_str_radix(parts.next()?, 16).ok()?;
+    let pid = u16::from_str_radix(parts.next()?, 16).ok()?;
+    Some(UsbId::new(vid, pid, parts.next()))
+}
+
+/// Everything needed to open a connection to a display.
+#[derive(Debug, Clone)]
+pub struct DeviceConfig {
+    pub port: PortSelector,
+    pub baud_rate: u32,
+    /// Native (portrait) resolution of the panel.
+    pub width: u16,
+    pub height: u16,
+    pub timeout: Duration,
+    /// Protocol to speak, or `None` to work it out from the hello response.
+    pub revision: Option<Revision>,
+    pub reconnect: ReconnectPolicy,
+    pub stream: StreamConfig,
+    /// Appends all serial traffic to this trace file.
+    pub trace: Option<PathBuf>,
+}
+
+/// How hard to try reopening the port after the display drops off the bus.
+#[derive(Debug, Clone)]
+pub struct ReconnectPolicy {
+    /// Give up after this many attempts. Zero disables reconnecting.
+    pub max_attempts: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// Reset the panel when a write times out, on panels that support it.
+    pub reset_on_timeout: bool,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        ReconnectPolicy {
+            max_attempts: 10,
+            initial_backoff: Duration::from_millis(250),
+            max_backoff: Duration::from_secs(10),
+            reset_on_timeout: true,
+        }
+    }
+}
+
+/// How bitmaps are split up while streaming them to the panel.
+#[derive(Debug, Clone)]
+pub struct StreamConfig {
+    /// Rows of the region sent per write.
+    pub rows_per_chunk: u16,
+    /// Timeout for each chunk write, or `None` to use the device timeout.
+    pub chunk_timeout: Option<Duration>,
+    /// Pause between chunks, for hubs that drop data when it arrives too fast. Only the
+    /// first chunk is sent straight away; `Device::poll` sends each of the rest once due.
+    pub chunk_delay: Option<Duration>,
+}
+
+impl Default for StreamConfig {
+    fn default() -> Self {
+        StreamConfig {
+            rows_per_chunk: 2,
+            chunk_timeout: None,
+            chunk_delay: None,
+        }
+    }
+}
+
+/// Throughput of bitmap uploads.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StreamStats {
+    pub uploads: u64,
+    pub chunks: u64,
+    pub bytes: u64,
+    pub elapsed: Duration,
+}
+
+impl StreamStats {
+    /// Average bytes per second while streaming.
+    pub fn throughput(&self) -> f64 {
+        if self.elapsed.is_zero() {
+            return 0.0;
+        }
+        self.bytes as f64 / self.elapsed.as_secs_f64()
+    }
+
+    fn add(&mut self, other: &StreamStats) {
+        self.uploads += other.uploads;
+        self.chunks += other.chunks;
+        self.bytes += other.bytes;
+        self.elapsed += other.elapsed;
+    }
+}
+
+impl fmt::Display for StreamStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} bytes in {} chunks over {} uploads, {:.1} KiB/s",
+            self.bytes,
+            self.chunks,
+            self.uploads,
+            self.throughput() / 1024.0
+        )
+    }
+}
+
+impl Default for DeviceConfig {
+    fn default() -> Self {
+        DeviceConfig {
+            port: PortSelector::Usb(UsbId::TURING_3_5),
+            baud_rate: 115200,
+            width: 320,
+            height: 480,
+            timeout: Duration::from_secs(3),
+            revision: None,
+            reconnect: ReconnectPolicy::default(),
+            stream: StreamConfig::default(),
+            trace: None,
+        }
+    }
+}
+
+impl DeviceConfig {
+    /// The 800x480 Turing Smart Screen 5", found by its USB id. Naming the revision
+    /// skips probing the panel for it.
+    pub fn turing_5() -> Self {
+        DeviceConfig {
+            port: PortSelector::Usb(UsbId::TURING_5),
+            width: 480,
+            height: 800,
+            revision: Some(Revision::C),
+            ..Default::default()
+        }
+    }
+
+    /// Every model that can be found by its USB id, each with its own settings.
+    pub fn known_models() -> Vec<Self> {
+        vec![Self::turing_5(), Self::default()]
+    }
+
+    /// Uses `port`, as understood by `PortSelector::parse`, instead of searching USB.
+    pub fn with_port(port: &str) -> Self {
+        DeviceConfig {
+            port: PortSelector::parse(port),
+            ..Default::default()
+        }
+    }
+
+    fn open_serial(&self, port_name: &str) -> Result<Box<dyn Transport>, DeviceError> {
+        let serial = SerialTransport::open(port_name, self.baud_rate, self.timeout)
+            .map_err(|err| DeviceError::from_open(port_name, err))?;
+        Ok(Box::new(serial))
+    }
+
+    /// Opens the port on its own, without recording it.
+    pub fn open_port(&self) -> Result<Box<dyn Transport>, DeviceError> {
+        let transport: Box<dyn Transport> = match &self.port {
+            PortSelector::Path(path) => self.open_serial(path)?,
+            PortSelector::Usb(id) => self.open_serial(&find_port(id)?)?,
+            PortSelector::Tcp(addr) => Box::new(
+                TcpTransport::connect(addr.as_str(), self.timeout).map_err(DeviceError::from_io)?,
+            ),
+            PortSelector::File(path) => {
+                Box::new(FileTransport::create(path).map_err(DeviceError::from_io)?)
+            }
+        };
+        Ok(transport)
+    }
+
+    /// Opens the port, wrapped in a recorder if tracing is enabled. The trace is tagged
+    /// with the revision the panel speaks so it can be decoded later.
+    pub fn open_transport(&self, revision: Revision) -> Result<Box<dyn Transport>, DeviceError> {
+        self.record(self.open_port()?, revision)
+    }
+
+    fn record(
+        &self,
+        transport: Box<dyn Transport>,
+        revision: Revision,
+    ) -> Result<Box<dyn Transport>, DeviceError> {
+        match &self.trace {
+            Some(path) => {
+                let recorder = RecordingTransport::open(transport, path, revision)
+                    .map_err(DeviceError::from_io)?;
+                Ok(Box::new(recorder))
+            }
+            None => Ok(transport),
+        }
+    }
+}
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(3);
+/// How long a panel takes to come back after a reset.
+const RESET_DELAY: Duration = Duration::from_secs(5);
+
+fn create_protocol(revision: Revision) -> Box<dyn Protocol> {
+    match revision {
+        Revision::A => Box::new(RevA::new()),
+        Revision::B => Box::new(RevB::new(0)),
+        Revision::C => Box::new(RevC::new()),
+    }
+}
+
+/// Probes the panel for its protocol, then restores the normal timeout.
+fn detect_protocol(
+    transport: &mut dyn Transport,
+    timeout: Duration,
+) -> Result<Box<dyn Protocol>, DeviceError> {
+    let protocol = detect(transport).map_err(DeviceError::from_io)?;
+    transport
+        .set_timeout(timeout)
+        .map_err(DeviceError::from_io)?;
+
+    Ok(protocol)
+}
+
+/// How far the device has got with bringing a failed link back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Recovery {
+    /// The panel was told to restart and should be back at `ready_at`.
+    Resetting { ready_at: Instant },
+    /// `attempt` tries at reopening the port have failed; the next is due at `retry_at`.
+    Reconnecting { attempt: u32, retry_at: Instant },
+}
+
+impl Recovery {
+    /// When the next step can be taken.
+    pub fn due(&self) -> Instant {
+        match self {
+            Recovery::Resetting { ready_at } => *ready_at,
+            Recovery::Reconnecting { retry_at, .. } => *retry_at,
+        }
+    }
+}
+
+impl fmt::Display for Recovery {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Recovery::Resetting { .. } => write!(f, "resetting the display"),
+            Recovery::Reconnecting { attempt, .. } => {
+                write!(f, "reconnecting (attempt {})", attempt + 1)
+            }
+        }
+    }
+}
+
+/// A paced bitmap upload with chunks still to send.
+struct Upload {
+    encoded: Vec<u8>,
+    chunk_size: usize,
+    sent: usize,
+    next_chunk: Instant,
+    started: Instant,
+    stats: StreamStats,
+}
+
+/// Polls a shared device until it is no longer waiting on anything, sleeping in between
+/// with the lock released so other threads can keep using it. Fails only when recovering
+/// the link is given up on.
+pub fn settle(device: &Mutex<Device>) -> Result<(), DeviceError> {
+    loop {
+        let next = device.lock().unwrap().poll()?;
+        match next {
+            Some(due) => thread::sleep(due.saturating_duration_since(Instant::now())),
+            None => return Ok(()),
+        }
+    }
+}
+
+/// Stands in for a transport that has been torn down.
+struct Closed;
+
+impl Transport for Closed {
+    fn write_all(&mut self, _data: &[u8]) -> std::io::Result<()> {
+        Err(std::io::ErrorKind::NotConnected.into())
+    }
 }
 
 pub struct Device {
@@ -50,91 +467,145 @@ pub struct Device {
     height: u16,
     device_width: u16,
     device_height: u16,
-    serial_connection: Box<dyn SerialPort>,
+    transport: Box<dyn Transport>,
+    protocol: Box<dyn Protocol>,
+    info: DeviceInfo,
+    timeout: Duration,
+    // Only set when the transport can be reopened after a disconnect
+    config: Option<DeviceConfig>,
+    // Last state sent to the panel, replayed after reconnecting
+    brightness: u8,
+    orientation: Orientation,
+    screen_on: bool,
+    frame: Vec<u8>, // RGB565 copy of the screen: width * height * 2
+    frame_valid: bool,
+    stream: StreamConfig,
+    dither: Dither, // For pictures loaded from disk
+    scaling: Scaling,
+    last_upload: StreamStats,
+    total_uploads: StreamStats,
+    // What went wrong and how far getting the link back has got
+    recovering: Option<(Recovery, String)>,
+    upload: Option<Upload>,
 }
 
 impl Device {
-    pub fn new() -> Result<Self, DeviceError> {
-        let width = 320;
-        let height = 480;
-        let port_name = "COM4";
-        let baud_rate = 115200;
-
-        let open_serial = serialport::new(port_name, baud_rate)
-            .timeout(Duration::from_secs(3))
-            .open()
-            .map_err(|err| DeviceError::PacketError(format!("Packet error: {}", err)))?;
-
-        let mut device = Self {
-            width,
-            height,
-            device_width: width,
-            device_height: height,
-            serial_connection: open_serial,
+    pub fn new(config: DeviceConfig) -> Result<Self, DeviceError> {
+        // Probing happens before the recorder goes on, the trace only needs the revision
+        let mut transport = config.open_port()?;
+        let protocol = match config.revision {
+            Some(revision) => create_protocol(revision),
+            None => detect_protocol(transport.as_mut(), config.timeout)?,
         };
+        let transport = config.record(transport, protocol.revision())?;
 
+        let mut device = Self::create(transport, protocol, config.width, config.height);
+        device.timeout = config.timeout;
+        device.stream = config.stream.clone();
+        device.config = Some(config);
         device.init()?;
 
         Ok(device)
     }
 
-    fn init(&mut self) -> Result<(), DeviceError> {
-        let hello_packet = create_hello_packet();
-        self.serial_connection
-            .write_all(&hello_packet)
-            .map_err(DeviceError::IoError)?;
+    /// Drives a display over any transport, speaking the given protocol without probing
+    /// for it. `width` and `height` are the panel's native (portrait) resolution. Such
+    /// devices are not reconnected if the transport fails.
+    pub fn with_protocol(
+        transport: Box<dyn Transport>,
+        protocol: Box<dyn Protocol>,
+        width: u16,
+        height: u16,
+    ) -> Result<Self, DeviceError> {
+        let mut device = Self::create(transport, protocol, width, height);
+        device.init()?;
 
-        self.set_brightness(10)?;
-        self.screen_on()?;
-        self.set_brightness(10)?;
-        self.set_orientation(Orientation::Portrait)
+        Ok(device)
     }
 
-    pub fn screen_black(&mut self) -> Result<(), DeviceError> {
-        self.set_orientation(Orientation::Portrait)?;
-
-        let screen_black_packet = create_screen_black_packet();
-        self.serial_connection
-            .write_all(&screen_black_packet)
-            .map_err(DeviceError::IoError)
+    fn create(
+        transport: Box<dyn Transport>,
+        protocol: Box<dyn Protocol>,
+        width: u16,
+        height: u16,
+    ) -> Self {
+        Self {
+            width,
+            height,
+            device_width: width,
+            device_height: height,
+            transport,
+            info: DeviceInfo {
+                revision: protocol.revision(),
+                sub_revision: String::new(),
+                resolution: None,
+            },
+            protocol,
+            timeout: DEFAULT_TIMEOUT,
+            config: None,
+            brightness: 10,
+            orientation: Orientation::Portrait,
+            screen_on: true,
+            frame: vec![0; width as usize * height as usize * 2],
+            frame_valid: false,
+            stream: StreamConfig::default(),
+            dither: Dither::default(),
+            scaling: Scaling::default(),
+            last_upload: StreamStats::default(),
+            total_uploads: StreamStats::default(),
+            recovering: None,
+            upload: None,
+        }
     }
 
-    pub fn screen_white(&mut self) -> Result<(), DeviceError> {
-        self.set_orientation(Orientation::Portrait)?;
-
-        let screen_white_packet = create_screen_white_packet();
-        self.serial_connection
-            .write_all(&screen_white_packet)
-            .map_err(DeviceError::IoError)
+    fn send(&mut self, data: &[u8]) -> Result<(), DeviceError> {
+        self.transport.write_all(data).map_err(DeviceError::from_io)
     }
 
-    pub fn screen_on(&mut self) -> Result<(), DeviceError> {
-        let on_packet = create_screen_on_packet();
+    pub fn revision(&self) -> Revision {
+        self.protocol.revision()
+    }
 
-        self.serial_connection
-            .write_all(&on_packet)
-            .map_err(DeviceError::IoError)
+    /// What the panel reported about itself during the last hello.
+    pub fn info(&self) -> &DeviceInfo {
+        &self.info
     }
 
-    pub fn screen_off(&mut self) -> Result<(), DeviceError> {
-        let off_packet = create_screen_off_packet();
+    fn read_hello_reply(&mut self) -> Result<Vec<u8>, DeviceError> {
+        let reply_len = self.protocol.hello_reply_len();
+        if reply_len == 0 {
+            return Ok(Vec::new());
+        }
+
+        self.transport
+            .set_timeout(PROBE_TIMEOUT)
+            .map_err(DeviceError::from_io)?;
+        let reply = read_reply(self.transport.as_mut(), reply_len);
+        self.transport
+            .set_timeout(self.timeout)
+            .map_err(DeviceError::from_io)?;
 
-        self.serial_connection
-            .write_all(&off_packet)
-            .map_err(DeviceError::IoError)
+        reply.map_err(DeviceError::from_io)
     }
 
-    pub fn set_brightness(&mut self, brightness: u8) -> Result<(), DeviceError> {
-        let brightness_packet = create_screen_brightness_packet(brightness)
-            .map_err(|err| DeviceError::PacketError(format!("Packet error: {}", err)))?;
+    /// Switches to the native resolution the panel reported, if it reported one.
+    fn apply_info(&mut self, info: DeviceInfo) {
+        if let Some((width, height)) = info.resolution
+            && (width, height) != (self.device_width, self.device_height)
+        {
+            self.device_width = width;
+            self.device_height = height;
+            self.frame = vec![0; width as usize * height as usize * 2];
+            self.frame_valid = false;
+        }
 
-        self.serial_connection
-            .write_all(&brightness_packet)
-            .map_err(DeviceError::IoError)
+        self.update_size();
+        self.info = info;
     }
 
-    pub fn set_orientation(&mut self, orientation: Orientation) -> Result<(), DeviceError> {
-        match orientation {
+    /// Works out the screen size in the current orientation.
+    fn update_size(&mut self) {
+        match self.orientation {
             Orientation::Portrait | Orientation::ReversePortrait => {
                 self.width = self.device_width;
                 self.height = self.device_height;
@@ -144,43 +615,360 @@ impl Device {
                 self.height = self.device_width;
             }
         }
+    }
+
+    /// Sends the current brightness, power and orientation state.
+    fn init(&mut self) -> Result<(), DeviceError> {
+        let hello_packet = self.protocol.hello();
+        self.send(&hello_packet)?;
+
+        let reply = self.read_hello_reply()?;
+        let info = self
+            .protocol
+            .hello_reply(&reply)
+            .map_err(DeviceError::ProtocolMismatch)?;
+        self.apply_info(info);
+
+        let brightness_packet = self
+            .protocol
+            .brightness(self.brightness)
+            .map_err(DeviceError::InvalidArgument)?;
+        self.send(&brightness_packet)?;
+
+        let power_packet = if self.screen_on {
+            self.protocol.screen_on()
+        } else {
+            self.protocol.screen_off()
+        };
+        self.send(&power_packet)?;
+        self.send(&brightness_packet)?;
+
+        let orientation_packet =
+            self.protocol
+                .orientation(self.orientation, self.width, self.height);
+        self.send(&orientation_packet)
+    }
 
-        let orientation_packet = create_orientation_packet(orientation, self.width, self.height);
+    /// Runs a command against the panel. The device state must already include the
+    /// command's effect, so if the link drops part way through, reconnecting and
+    /// replaying that state completes it.
+    fn run<F>(&mut self, command: F) -> Result<(), DeviceError>
+    where
+        F: FnOnce(&mut Self) -> Result<(), DeviceError>,
+    {
+        if let Some((recovery, cause)) = &self.recovering {
+            return Err(DeviceError::Recovering {
+                cause: cause.clone(),
+                recovery: *recovery,
+            });
+        }
 
-        self.serial_connection
-            .write_all(&orientation_packet)
-            .map_err(DeviceError::IoError)
+        // Anything sent mid-upload would be taken for pixels, so finish it unpaced first
+        let result = self.send_chunks(false).and_then(|()| command(self));
+        self.recover_from(result)
     }
 
-    pub fn set_background_picture(&mut self, path: &str) -> Result<(), DeviceError> {
-        let display_packet = create_display_image_packet(0, 0, self.width, self.height);
+    /// Starts getting the link back if `result` failed in a way that might allow it.
+    fn recover_from(&mut self, result: Result<(), DeviceError>) -> Result<(), DeviceError> {
+        let err = match result {
+            Err(err) if err.is_recoverable() && self.can_reconnect() => err,
+            result => return result,
+        };
+        self.upload = None;
 
-        self.serial_connection
-            .write_all(&display_packet)
-            .map_err(DeviceError::IoError)?;
+        let now = Instant::now();
+        let recovery = match (&err, self.protocol.reset()) {
+            // A write timing out usually means the panel has hung
+            (DeviceError::Timeout(_), Some(reset_packet)) if self.can_reset() => {
+                // A hung panel may not take the command, the port is reopened anyway
+                self.send(&reset_packet).ok();
+                Recovery::Resetting {
+                    ready_at: now + RESET_DELAY,
+                }
+            }
+            _ => Recovery::Reconnecting {
+                attempt: 0,
+                retry_at: now + self.reconnect_policy().initial_backoff,
+            },
+        };
 
-        let image_data = load_png_to_rgb565(path, self.width as u32, self.height as u32)
-            .map_err(|err| DeviceError::ImageError(format!("Image error: {}", err)))?;
+        // Release the old port first, some platforms refuse to open it twice
+        self.transport = Box::new(Closed);
+
+        let cause = err.to_string();
+        self.recovering = Some((recovery, cause.clone()));
+        Err(DeviceError::Recovering { cause, recovery })
+    }
 
-        // Replace this with your command to send the image data
-        let total_size = image_data.len();
-        let rows_per_chunk = 2;
-        let chunk_size = 480 * 2 * rows_per_chunk;
-        let mut start = 0;
+    fn reconnect_policy(&self) -> ReconnectPolicy {
+        self.config
+            .as_ref()
+            .map(|config| config.reconnect.clone())
+            .unwrap_or_default()
+    }
+
+    fn can_reconnect(&self) -> bool {
+        self.config
+            .as_ref()
+            .is_some_and(|config| config.reconnect.max_attempts > 0)
+    }
+
+    fn can_reset(&self) -> bool {
+        self.can_reconnect()
+            && self.protocol.reset().is_some()
+            && self
+                .config
+                .as_ref()
+                .is_some_and(|config| config.reconnect.reset_on_timeout)
+    }
+
+    /// Checks the panel still answers, to catch one that takes writes but has stopped
+    /// updating. A panel that stays silent is treated like a write timing out: it is reset
+    /// if it can be, otherwise reconnected. Does nothing for panels with no command they
+    /// answer.
+    pub fn check_alive(&mut self) -> Result<(), DeviceError> {
+        let Some(ping_packet) = self.protocol.ping() else {
+            return Ok(());
+        };
+
+        self.run(|dev| {
+            // Whatever the panel sent unasked would be taken for the answer
+            dev.transport
+                .discard_input()
+                .map_err(DeviceError::from_io)?;
+            dev.send(&ping_packet)?;
+
+            dev.transport
+                .set_timeout(PROBE_TIMEOUT)
+                .map_err(DeviceError::from_io)?;
+            let reply = read_reply(dev.transport.as_mut(), 1);
+            dev.transport
+                .set_timeout(dev.timeout)
+                .map_err(DeviceError::from_io)?;
+
+            match reply.map_err(DeviceError::from_io)? {
+                reply if reply.is_empty() => Err(DeviceError::Timeout(std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    "panel stopped answering",
+                ))),
+                _ => Ok(()),
+            }
+        })
+    }
 
-        while start < total_size {
-            let end = (start + chunk_size).min(total_size);
-            let chunk = &image_data[start..end];
+    /// Restarts the panel. Commands fail with `DeviceError::Recovering` until `poll` has
+    /// brought it back, reopening the port if possible and restoring brightness,
+    /// orientation and the last frame.
+    pub fn reset(&mut self) -> Result<(), DeviceError> {
+        let reset_packet = self.protocol.reset().ok_or_else(|| {
+            DeviceError::ProtocolMismatch(format!(
+                "Revision {:?} panels have no reset command",
+                self.revision()
+            ))
+        })?;
 
-            self.serial_connection
-                .write_all(&chunk)
-                .map_err(DeviceError::IoError)?;
+        self.run(|dev| dev.send(&reset_packet))?;
 
-            start = end;
+        // The panel drops off the bus while it restarts
+        if self.can_reconnect() {
+            self.transport = Box::new(Closed);
         }
+        let recovery = Recovery::Resetting {
+            ready_at: Instant::now() + RESET_DELAY,
+        };
+        self.recovering = Some((recovery, "Reset requested".to_string()));
         Ok(())
     }
 
+    /// Does whatever the device was left waiting on: the next step of getting the link
+    /// back, or the next chunk of a paced upload. Never sleeps. Returns when it next wants
+    /// calling, or `None` once there is nothing left to do.
+    pub fn poll(&mut self) -> Result<Option<Instant>, DeviceError> {
+        let now = Instant::now();
+
+        if let Some((recovery, _)) = self.recovering {
+            if recovery.due() <= now {
+                self.recover(recovery)?;
+            }
+        } else if let Some(upload) = &self.upload
+            && upload.next_chunk <= now
+        {
+            let result = self.send_chunks(true);
+            // A failure starts recovery, which the next poll picks up
+            if let Err(err) = self.recover_from(result)
+                && !matches!(err, DeviceError::Recovering { .. })
+            {
+                return Err(err);
+            }
+        }
+
+        Ok(self.waiting_until())
+    }
+
+    /// Whether the link has failed, or the panel is restarting, and isn't back yet.
+    pub fn is_recovering(&self) -> bool {
+        self.recovering.is_some()
+    }
+
+    /// When the device next needs polling, if it is waiting on anything.
+    fn waiting_until(&self) -> Option<Instant> {
+        match (&self.recovering, &self.upload) {
+            (Some((recovery, _)), _) => Some(recovery.due()),
+            (None, Some(upload)) => Some(upload.next_chunk),
+            (None, None) => None,
+        }
+    }
+
+    /// Takes one step of getting the link back: reopens the port if there is one, then
+    /// replays the device state. Failures schedule another attempt with exponential
+    /// backoff until the policy runs out.
+    fn recover(&mut self, recovery: Recovery) -> Result<(), DeviceError> {
+        let attempt = match recovery {
+            Recovery::Resetting { .. } => 0,
+            Recovery::Reconnecting { attempt, .. } => attempt,
+        };
+
+        let result = match self.config.clone() {
+            Some(config) if self.can_reconnect() => config
+                .open_transport(self.revision())
+                .and_then(|transport| {
+                    self.transport = transport;
+                    self.replay()
+                }),
+            // Nothing to reopen, the panel comes back on the same transport
+            _ => self.replay(),
+        };
+
+        let err = match result {
+            Ok(()) => {
+                self.recovering = None;
+                return Ok(());
+            }
+            Err(err) => err,
+        };
+
+        let policy = self.reconnect_policy();
+        // No point retrying something like a permissions problem
+        if !err.is_recoverable() || !self.can_reconnect() || attempt + 1 >= policy.max_attempts {
+            self.recovering = None;
+            return Err(err);
+        }
+
+        self.transport = Box::new(Closed);
+        let backoff = policy
+            .initial_backoff
+            .saturating_mul(2u32.saturating_pow(attempt + 1))
+            .min(policy.max_backoff);
+        if let Some((recovery, _)) = &mut self.recovering {
+            *recovery = Recovery::Reconnecting {
+                attempt: attempt + 1,
+                retry_at: Instant::now() + backoff,
+            };
+        }
+        Ok(())
+    }
+
+    fn replay(&mut self) -> Result<(), DeviceError> {
+        self.init()?;
+
+        if self.frame_valid {
+            let frame = std::mem::take(&mut self.frame);
+            let result = self.stream_picture(&frame, 0, 0, self.width, self.height);
+            self.frame = frame;
+            result?;
+        }
+
+        Ok(())
+    }
+
+    pub fn screen_black(&mut self) -> Result<(), DeviceError> {
+        self.screen_fill(false)
+    }
+
+    pub fn screen_white(&mut self) -> Result<(), DeviceError> {
+        self.screen_fill(true)
+    }
+
+    fn screen_fill(&mut self, white: bool) -> Result<(), DeviceError> {
+        let value = if white { 0xFF } else { 0x00 };
+
+        match self.protocol.screen_fill(white) {
+            Some(fill_packet) => {
+                self.frame.fill(value);
+                self.frame_valid = true;
+
+                // The fill command only works in portrait, so switch for it and back again
+                let portrait = self.protocol.orientation(
+                    Orientation::Portrait,
+                    self.device_width,
+                    self.device_height,
+                );
+                let restore = self
+                    .protocol
+                    .orientation(self.orientation, self.width, self.height);
+
+                self.run(|dev| {
+                    dev.send(&portrait)?;
+                    dev.send(&fill_packet)?;
+                    dev.send(&restore)
+                })
+            }
+            None => {
+                // No fill command on this panel, so send a solid bitmap instead
+                let image_data = vec![value; self.frame.len()];
+                self.display_picture(image_data, 0, 0, self.width, self.height)
+            }
+        }
+    }
+
+    pub fn screen_on(&mut self) -> Result<(), DeviceError> {
+        self.screen_on = true;
+        let on_packet = self.protocol.screen_on();
+
+        self.run(|dev| dev.send(&on_packet))
+    }
+
+    pub fn screen_off(&mut self) -> Result<(), DeviceError> {
+        self.screen_on = false;
+        let off_packet = self.protocol.screen_off();
+
+        self.run(|dev| dev.send(&off_packet))
+    }
+
+    pub fn set_brightness(&mut self, brightness: u8) -> Result<(), DeviceError> {
+        let brightness_packet = self
+            .protocol
+            .brightness(brightness)
+            .map_err(DeviceError::InvalidArgument)?;
+        self.brightness = brightness;
+
+        self.run(|dev| dev.send(&brightness_packet))
+    }
+
+    pub fn set_orientation(&mut self, orientation: Orientation) -> Result<(), DeviceError> {
+        if self.orientation != orientation {
+            // The old frame no longer lines up with the screen
+            self.frame.fill(0);
+            self.frame_valid = false;
+        }
+        self.orientation = orientation;
+        self.update_size();
+
+        let orientation_packet = self
+            .protocol
+            .orientation(orientation, self.width, self.height);
+
+        self.run(|dev| dev.send(&orientation_packet))
+    }
+
+    pub fn set_background_picture(&mut self, path: &str) -> Result<(), DeviceError> {
+        let (width, height) = (self.width as u32, self.height as u32);
+        let image_data = load_png_to_rgb565(path, width, height, self.scaling, self.dither)
+            .map_err(|err| DeviceError::ImageError(format!("Image error: {}", err)))?;
+
+        self.display_picture(image_data, 0, 0, self.width, self.height)
+    }
+
     pub fn display_picture(
         &mut self,
         image_data: Vec<u8>,
@@ -189,30 +977,163 @@ impl Device {
         width: u16,
         height: u16,
     ) -> Result<(), DeviceError> {
-        let display_packet = create_display_image_packet(x, y, width, height);
+        // A bad region would leave the panel waiting for pixels that never come
+        region_end(x, y, width, height, self.width as u32, self.height as u32)
+            .map_err(DeviceError::EncodeError)?;
+        check_payload(width, height, 2, image_data.len()).map_err(DeviceError::EncodeError)?;
+
+        self.update_frame(&image_data, x, y, width, height);
+
+        self.run(|dev| dev.stream_picture(&image_data, x, y, width, height))
+    }
+
+    /// Copies a region into the local copy of the screen.
+    fn update_frame(&mut self, image_data: &[u8], x: u16, y: u16, width: u16, height: u16) {
+        let (x, y, width) = (x as usize, y as usize, width as usize);
+        let screen_width = self.width as usize;
+        if x >= screen_width {
+            return;
+        }
+        let copy_width = width.min(screen_width - x);
+
+        for row in 0..(height as usize).min((self.height as usize).saturating_sub(y)) {
+            let src = row * width * 2;
+            let dst = ((y + row) * screen_width + x) * 2;
+            if src + copy_width * 2 > image_data.len() {
+                break;
+            }
+            self.frame[dst..dst + copy_width * 2]
+                .copy_from_slice(&image_data[src..src + copy_width * 2]);
+        }
+
+        if x == 0 && y == 0 && width == screen_width && height == self.height {
+            self.frame_valid = true;
+        }
+    }
+
+    /// Sends a bitmap header followed by the encoded pixels, a few rows per write. With
+    /// `chunk_delay` set the upload is left pending after the first chunk.
+    fn stream_picture(
+        &mut self,
+        image_data: &[u8],
+        x: u16,
+        y: u16,
+        width: u16,
+        height: u16,
+    ) -> Result<(), DeviceError> {
+        let display_packet = self
+            .protocol
+            .display_image(x, y, width, height)
+            .map_err(DeviceError::EncodeError)?;
+        self.send(&display_packet)?;
 
-        self.serial_connection
-            .write_all(&display_packet)
-            .map_err(DeviceError::IoError)?;
+        let encoded = self.protocol.encode_pixels(x, y, width, height, image_data);
+        let row_size = encoded.len().div_ceil(height.max(1) as usize);
+        let chunk_size = (row_size * self.stream.rows_per_chunk.max(1) as usize).max(1);
+
+        let now = Instant::now();
+        self.upload = Some(Upload {
+            encoded: encoded.into_owned(),
+            chunk_size,
+            sent: 0,
+            next_chunk: now,
+            started: now,
+            stats: StreamStats {
+                uploads: 1,
+                ..Default::default()
+            },
+        });
+
+        // With pacing, only the first chunk goes now and `poll` sends the rest
+        self.send_chunks(self.stream.chunk_delay.is_some())
+    }
+
+    /// Sends the pending upload, or just its next chunk if `paced`, and finishes it off
+    /// once every chunk is out.
+    fn send_chunks(&mut self, paced: bool) -> Result<(), DeviceError> {
+        let Some(mut upload) = self.upload.take() else {
+            return Ok(());
+        };
+
+        if let Some(timeout) = self.stream.chunk_timeout {
+            self.transport
+                .set_timeout(timeout)
+                .map_err(DeviceError::from_io)?;
+        }
+
+        let mut result = Ok(());
+        while upload.sent < upload.encoded.len() {
+            let end = (upload.sent + upload.chunk_size).min(upload.encoded.len());
+            result = self.send(&upload.encoded[upload.sent..end]);
+            if result.is_err() {
+                break;
+            }
+            upload.stats.chunks += 1;
+            upload.stats.bytes += (end - upload.sent) as u64;
+            upload.sent = end;
+
+            if paced {
+                break;
+            }
+        }
 
-        let total_size = image_data.len();
-        let rows_per_chunk = 2;
-        let chunk_size = 480 * 2 * rows_per_chunk;
-        let mut start = 0;
+        if self.stream.chunk_timeout.is_some() {
+            self.transport
+                .set_timeout(self.timeout)
+                .map_err(DeviceError::from_io)?;
+        }
 
-        while start < total_size {
-            let end = (start + chunk_size).min(total_size);
-            let chunk = &image_data[start..end];
+        if result.is_ok()
+            && upload.sent < upload.encoded.len()
+            && let Some(delay) = self.stream.chunk_delay
+        {
+            upload.next_chunk = Instant::now() + delay;
+            self.upload = Some(upload);
+            return Ok(());
+        }
 
-            self.serial_connection
-                .write_all(&chunk)
-                .map_err(DeviceError::IoError)?;
+        let mut stats = upload.stats;
+        stats.elapsed = upload.started.elapsed();
+        self.last_upload = stats;
+        self.total_uploads.add(&stats);
+        result?;
 
-            start = end;
+        let end_packet = self.protocol.display_image_end();
+        if !end_packet.is_empty() {
+            self.send(&end_packet)?;
+            // Status replies aren't needed, just keep them from piling up
+            self.transport
+                .discard_input()
+                .map_err(DeviceError::from_io)?;
         }
         Ok(())
     }
 
+    /// Sets how pictures loaded by `set_background_picture` are reduced to RGB565.
+    pub fn set_dither(&mut self, dither: Dither) {
+        self.dither = dither;
+    }
+
+    /// Sets how pictures loaded by `set_background_picture` are fitted to the screen.
+    pub fn set_scaling(&mut self, scaling: Scaling) {
+        self.scaling = scaling;
+    }
+
+    /// Changes how future bitmaps are chunked.
+    pub fn set_stream_config(&mut self, stream: StreamConfig) {
+        self.stream = stream;
+    }
+
+    /// Throughput of the most recent bitmap upload.
+    pub fn last_upload_stats(&self) -> StreamStats {
+        self.last_upload
+    }
+
+    /// Throughput of every bitmap upload since the device was opened.
+    pub fn stream_stats(&self) -> StreamStats {
+        self.total_uploads
+    }
+
     pub fn get_width(&self) -> u16 {
         self.width
     }
@@ -221,3 +1142,389 @@ impl Device {
         self.height
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image_extensions::ScaleMode;
+    use crate::packets::Packet;
+    use crate::transport::MemoryTransport;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    fn open(memory: &MemoryTransport) -> Device {
+        let mut device =
+            Device::with_protocol(Box::new(memory.clone()), Box::new(RevA::new()), 4, 4).unwrap();
+        memory.take_written();
+        device.set_stream_config(StreamConfig {
+            rows_per_chunk: 1,
+            chunk_timeout: None,
+            chunk_delay: Some(Duration::ZERO),
+        });
+        device
+    }
+
+    #[test]
+    fn port_selectors_are_parsed() {
+        match PortSelector::parse("usb:1a86:5722:ABC") {
+            PortSelector::Usb(id) => assert_eq!(id, UsbId::new(0x1A86, 0x5722, Some("ABC"))),
+            other => panic!("parsed {:?}", other),
+        }
+        assert!(matches!(
+            PortSelector::parse("usb:1d6b:0121"),
+            PortSelector::Usb(UsbId {
+                serial_number: None,
+                ..
+            })
+        ));
+        assert!(matches!(
+            PortSelector::parse("tcp:localhost:5000"),
+            PortSelector::Tcp(addr) if addr == "localhost:5000"
+        ));
+        assert!(matches!(
+            PortSelector::parse("file:/tmp/panel.bin"),
+            PortSelector::File(_)
+        ));
+        assert!(matches!(
+            PortSelector::parse("/dev/ttyACM0"),
+            PortSelector::Path(path) if path == "/dev/ttyACM0"
+        ));
+    }
+
+    #[test]
+    fn io_errors_are_classified() {
+        use std::io::ErrorKind;
+
+        for (kind, timeout, disconnected) in [
+            (ErrorKind::TimedOut, true, false),
+            (ErrorKind::WouldBlock, true, false),
+            (ErrorKind::BrokenPipe, false, true),
+            (ErrorKind::NotConnected, false, true),
+            (ErrorKind::ConnectionReset, false, true),
+            (ErrorKind::ConnectionAborted, false, true),
+            (ErrorKind::UnexpectedEof, false, true),
+            (ErrorKind::Other, false, false),
+        ] {
+            let err = DeviceError::from_io(kind.into());
+            assert_eq!(
+                matches!(err, DeviceError::Timeout(_)),
+                timeout,
+                "{:?}",
+                kind
+            );
+            assert_eq!(
+                matches!(err, DeviceError::Disconnected(_)),
+                disconnected,
+                "{:?}",
+                kind
+            );
+            assert!(err.is_recoverable(), "{:?}", kind);
+        }
+        assert!(matches!(
+            DeviceError::from_io(ErrorKind::Other.into()),
+            DeviceError::IoError(_)
+        ));
+    }
+
+    #[test]
+    fn open_errors_are_classified() {
+        use serialport::ErrorKind;
+        use std::io;
+
+        let open =
+            |kind| DeviceError::from_open("/dev/ttyACM9", serialport::Error::new(kind, "failed"));
+
+        for kind in [ErrorKind::NoDevice, ErrorKind::Io(io::ErrorKind::NotFound)] {
+            match open(kind) {
+                DeviceError::PortNotFound { wanted, .. } => assert_eq!(wanted, "/dev/ttyACM9"),
+                other => panic!("{:?} classified as {:?}", kind, other),
+            }
+        }
+        assert!(matches!(
+            open(ErrorKind::Io(io::ErrorKind::PermissionDenied)),
+            DeviceError::PermissionDenied { port } if port == "/dev/ttyACM9"
+        ));
+        assert!(matches!(
+            open(ErrorKind::InvalidInput),
+            DeviceError::InvalidArgument(_)
+        ));
+        assert!(matches!(
+            open(ErrorKind::Io(io::ErrorKind::TimedOut)),
+            DeviceError::Timeout(_)
+        ));
+        assert!(matches!(open(ErrorKind::Unknown), DeviceError::IoError(_)));
+    }
+
+    #[test]
+    fn only_errors_that_might_clear_up_are_recoverable() {
+        let io_error = || std::io::Error::from(std::io::ErrorKind::Other);
+        let recovery = Recovery::Reconnecting {
+            attempt: 0,
+            retry_at: Instant::now(),
+        };
+
+        for err in [
+            DeviceError::IoError(io_error()),
+            DeviceError::PortNotFound {
+                wanted: String::new(),
+                candidates: Vec::new(),
+            },
+            DeviceError::Timeout(io_error()),
+            DeviceError::Disconnected(io_error()),
+            DeviceError::Recovering {
+                cause: String::new(),
+                recovery,
+            },
+        ] {
+            assert!(err.is_recoverable(), "{:?}", err);
+        }
+
+        for err in [
+            DeviceError::ImageError(String::new()),
+            DeviceError::EncodeError(EncodeError::ZeroSize {
+                width: 0,
+                height: 0,
+            }),
+            DeviceError::PermissionDenied {
+                port: String::new(),
+            },
+            DeviceError::InvalidArgument(String::new()),
+            DeviceError::ProtocolMismatch(String::new()),
+        ] {
+            assert!(!err.is_recoverable(), "{:?}", err);
+        }
+    }
+
+    #[test]
+    fn paced_upload_sends_one_chunk_per_poll() {
+        let memory = MemoryTransport::new();
+        let mut device = open(&memory);
+
+        device
+            .display_picture(vec![1; 4 * 2 * 2], 0, 0, 4, 2)
+            .unwrap();
+        assert_eq!(memory.written().len(), 6 + 8);
+
+        assert!(device.poll().unwrap().is_none());
+        assert_eq!(memory.written().len(), 6 + 16);
+        assert_eq!(device.last_upload_stats().chunks, 2);
+    }
+
+    /// Saves a picture for `set_background_picture` to load.
+    fn picture_path(name: &str, picture: &image::RgbImage) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "gibmon-picture-{}-{}.png",
+            name,
+            std::process::id()
+        ));
+        picture.save(&path).unwrap();
+        path
+    }
+
+    #[test]
+    fn background_pictures_use_the_scaling_set() {
+        let memory = MemoryTransport::new();
+        let mut device = open(&memory);
+        let path = picture_path(
+            "scaling",
+            &image::RgbImage::from_pixel(4, 2, image::Rgb([255; 3])),
+        );
+        let path = path.to_str().unwrap();
+
+        device.set_background_picture(path).unwrap();
+        assert!(device.frame.iter().all(|&byte| byte == 0xFF));
+
+        // Fitting a wide picture leaves black bars above and below
+        device.set_scaling(Scaling::new(ScaleMode::Fit));
+        device.set_background_picture(path).unwrap();
+        let row = 4 * 2;
+        assert!(device.frame[..row].iter().all(|&byte| byte == 0));
+        assert!(device.frame[row..3 * row].iter().all(|&byte| byte == 0xFF));
+        assert!(device.frame[3 * row..].iter().all(|&byte| byte == 0));
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn background_pictures_use_the_dither_set() {
+        let memory = MemoryTransport::new();
+        let mut device = open(&memory);
+        // A shade that falls between two RGB565 levels
+        let gray = image::RgbImage::from_pixel(4, 4, image::Rgb([0x84; 3]));
+        let path = picture_path("dither", &gray);
+        let path = path.to_str().unwrap();
+
+        device.set_background_picture(path).unwrap();
+        let undithered = device.frame.clone();
+        assert!(undithered.chunks(2).all(|pixel| pixel == &undithered[..2]));
+
+        device.set_dither(Dither::Ordered);
+        device.set_background_picture(path).unwrap();
+        assert_ne!(device.frame, undithered);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn commands_finish_a_pending_upload_first() {
+        let memory = MemoryTransport::new();
+        let mut device = open(&memory);
+
+        device
+            .display_picture(vec![1; 4 * 4 * 2], 0, 0, 4, 4)
+            .unwrap();
+        device.screen_off().unwrap();
+
+        let written = memory.written();
+        assert_eq!(written.len(), 6 + 32 + 6);
+        assert_eq!(Packet::decode(&written[38..]), Ok((Packet::ScreenOff, 6)));
+        assert!(device.poll().unwrap().is_none());
+    }
+
+    #[test]
+    fn failures_without_a_port_to_reopen_are_returned() {
+        let memory = MemoryTransport::new();
+        let mut device = open(&memory);
+        device.transport = Box::new(Closed);
+
+        assert!(matches!(
+            device.screen_on(),
+            Err(DeviceError::Disconnected(_))
+        ));
+        assert!(device.poll().unwrap().is_none());
+    }
+
+    /// Answers every command with its model name until told to hang.
+    struct Answering {
+        hung: Arc<AtomicBool>,
+        reply: Vec<u8>,
+    }
+
+    impl Transport for Answering {
+        fn write_all(&mut self, _data: &[u8]) -> std::io::Result<()> {
+            if !self.hung.load(Ordering::SeqCst) {
+                self.reply = b"USBMONITOR35".to_vec();
+            }
+            Ok(())
+        }
+
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let count = buf.len().min(self.reply.len());
+            buf[..count].copy_from_slice(&self.reply[..count]);
+            self.reply.drain(..count);
+            Ok(count)
+        }
+
+        fn discard_input(&mut self) -> std::io::Result<()> {
+            self.reply.clear();
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn silent_panels_fail_the_liveness_check() {
+        let hung = Arc::new(AtomicBool::new(false));
+        let transport = Answering {
+            hung: hung.clone(),
+            reply: Vec::new(),
+        };
+        let mut device =
+            Device::with_protocol(Box::new(transport), Box::new(RevA::new()), 4, 4).unwrap();
+
+        device.check_alive().unwrap();
+
+        hung.store(true, Ordering::SeqCst);
+        assert!(matches!(device.check_alive(), Err(DeviceError::Timeout(_))));
+    }
+
+    #[test]
+    fn panels_that_never_answer_are_not_pinged() {
+        let memory = MemoryTransport::new();
+        let mut device = open(&memory);
+
+        device.check_alive().unwrap();
+        assert!(memory.written().is_empty());
+    }
+
+    #[test]
+    fn reset_panels_are_reopened_and_restored() {
+        let path = std::env::temp_dir().join(format!("gibmon-reset-{}", std::process::id()));
+        let mut device = Device::new(DeviceConfig {
+            port: PortSelector::File(path.clone()),
+            revision: Some(Revision::A),
+            width: 2,
+            height: 2,
+            ..Default::default()
+        })
+        .unwrap();
+        device
+            .display_picture(vec![9; 2 * 2 * 2], 0, 0, 2, 2)
+            .unwrap();
+
+        device.reset().unwrap();
+        assert!(device.is_recovering());
+        assert!(matches!(
+            device.screen_on(),
+            Err(DeviceError::Recovering {
+                recovery: Recovery::Resetting { .. },
+                ..
+            })
+        ));
+        // Commands sent before the reset are flushed out with the old port
+        let written = std::fs::read(&path).unwrap();
+        assert_eq!(
+            Packet::decode(&written[written.len() - 6..]),
+            Ok((Packet::Reset, 6))
+        );
+
+        // Skip waiting for the panel to come back
+        device.recovering = Some((
+            Recovery::Resetting {
+                ready_at: Instant::now(),
+            },
+            "Reset requested".to_string(),
+        ));
+        assert!(device.poll().unwrap().is_none());
+        assert!(!device.is_recovering());
+        drop(device);
+
+        // The port was opened afresh, then the state and last frame were replayed
+        let mut expected = RevA::new();
+        let mut replay = expected.hello();
+        let brightness = expected.brightness(10).unwrap();
+        replay.extend(&brightness);
+        replay.extend(expected.screen_on());
+        replay.extend(&brightness);
+        replay.extend(expected.orientation(Orientation::Portrait, 2, 2));
+        replay.extend(expected.display_image(0, 0, 2, 2).unwrap());
+        replay.extend([9; 2 * 2 * 2]);
+        assert_eq!(std::fs::read(&path).unwrap(), replay);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn backoff_is_recorded_while_reconnecting() {
+        let memory = MemoryTransport::new();
+        let mut device = open(&memory);
+        device.config = Some(DeviceConfig {
+            port: PortSelector::File("/nonexistent/gibmon/trace".into()),
+            ..Default::default()
+        });
+        device.transport = Box::new(Closed);
+
+        match device.set_brightness(50) {
+            Err(DeviceError::Recovering {
+                recovery: Recovery::Reconnecting { attempt: 0, .. },
+                ..
+            }) => {}
+            other => panic!("expected to start reconnecting, got {:?}", other),
+        }
+        // Nothing can happen until the backoff has passed
+        assert!(matches!(
+            device.screen_on(),
+            Err(DeviceError::Recovering { .. })
+        ));
+        assert!(device.poll().unwrap().is_some());
+    }
+}
@@ -0,0 +1,107 @@
+use crate::device::DeviceError;
+use serialport::{SerialPortInfo, SerialPortType};
+use std::fmt;
+
+/// Identifies a display by the USB descriptor of its serial bridge.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UsbId {
+    pub vid: u16,
+    pub pid: u16,
+    /// Only match ports reporting this serial string, if set.
+    pub serial_number: Option<String>,
+}
+
+impl UsbId {
+    /// Turing Smart Screen 3.5" (revision A).
+    pub const TURING_3_5: UsbId = UsbId {
+        vid: 0x1A86,
+        pid: 0x5722,
+        serial_number: None,
+    };
+
+    /// Turing Smart Screen 5" (revision C).
+    pub const TURING_5: UsbId = UsbId {
+        vid: 0x1D6B,
+        pid: 0x0121,
+        serial_number: None,
+    };
+
+    pub fn new(vid: u16, pid: u16, serial_number: Option<&str>) -> Self {
+        UsbId {
+            vid,
+            pid,
+            serial_number: serial_number.map(str::to_string),
+        }
+    }
+
+    fn matches(&self, port: &SerialPortInfo) -> bool {
+        match &port.port_type {
+            SerialPortType::UsbPort(info) => {
+                info.vid == self.vid
+                    && info.pid == self.pid
+                    && match &self.serial_number {
+                        Some(serial) => info.serial_number.as_deref() == Some(serial.as_str()),
+                        None => true,
+                    }
+            }
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for UsbId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "USB {:04x}:{:04x}", self.vid, self.pid)?;
+        if let Some(serial) = &self.serial_number {
+            write!(f, " serial \"{}\"", serial)?;
+        }
+        Ok(())
+    }
+}
+
+fn describe_port(port: &SerialPortInfo) -> String {
+    match &port.port_type {
+        SerialPortType::UsbPort(info) => match &info.serial_number {
+            Some(serial) => format!(
+                "{} (USB {:04x}:{:04x} serial \"{}\")",
+                port.port_name, info.vid, info.pid, serial
+            ),
+            None => format!("{} (USB {:04x}:{:04x})", port.port_name, info.vid, info.pid),
+        },
+        SerialPortType::PciPort => format!("{} (PCI)", port.port_name),
+        SerialPortType::BluetoothPort => format!("{} (Bluetooth)", port.port_name),
+        SerialPortType::Unknown => port.port_name.clone(),
+    }
+}
+
+/// Returns the names of every serial port matching `id`.
+pub fn find_ports(id: &UsbId) -> Result<Vec<String>, DeviceError> {
+    let ports = serialport::available_ports().map_err(|err| DeviceError::IoError(err.into()))?;
+
+    let matching: Vec<String> = ports
+        .iter()
+        .filter(|port| id.matches(port))
+        .map(|port| port.port_name.clone())
+        .collect();
+
+    if matching.is_empty() {
+        return Err(DeviceError::PortNotFound {
+            wanted: id.to_string(),
+            candidates: ports.iter().map(describe_port).collect(),
+        });
+    }
+
+    Ok(matching)
+}
+
+/// Describes every serial port present, for error messages.
+pub fn describe_ports() -> Vec<String> {
+    serialport::available_ports()
+        .map(|ports| ports.iter().map(describe_port).collect())
+        .unwrap_or_default()
+}
+
+/// Returns the first serial port matching `id`.
+pub fn find_port(id: &UsbId) -> Result<String, DeviceError> {
+    find_ports(id).map(|mut ports| ports.remove(0))
+}
@@ -0,0 +1,180 @@
+use crate::pixel_format::PixelFormat;
+
+/// How 8-bit channels are reduced to RGB565.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Dither {
+    /// Drop the low bits. Fastest, but gradients show bands.
+    #[default]
+    None,
+    /// Add a 4x4 Bayer pattern before dropping the low bits. The pattern is anchored to
+    /// screen coordinates, so regions converted separately still line up.
+    Ordered,
+    /// Spread each pixel's rounding error onto its neighbours. Smoothest, but the result
+    /// depends on the whole region, so partial updates can show seams.
+    FloydSteinberg,
+}
+
+impl Dither {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "none" => Some(Dither::None),
+            "ordered" => Some(Dither::Ordered),
+            "floyd-steinberg" => Some(Dither::FloydSteinberg),
+            _ => None,
+        }
+    }
+}
+
+const BAYER_4X4: [[i16; 4]; 4] = [[0, 8, 2, 10], [12, 4, 14, 6], [3, 11, 1, 9], [15, 7, 13, 5]];
+
+/// Bits dropped from red, green and blue.
+const DROPPED_BITS: [u32; 3] = [3, 2, 3];
+
+/// The 8-bit value a channel comes back as once quantized.
+fn quantize(value: i16, dropped_bits: u32) -> u8 {
+    let value = value.clamp(0, 255) as u8;
+    value & !((1u8 << dropped_bits) - 1)
+}
+
+/// Converts a `width` x `height` block of RGB or RGBA pixels to little-endian RGB565.
+/// `channels` is 3 or 4, any alpha is ignored. `origin` is where the block sits on the
+/// screen, which keeps ordered dithering aligned between blocks.
+pub fn to_rgb565(
+    pixels: &[u8],
+    channels: usize,
+    width: u32,
+    height: u32,
+    origin: (u32, u32),
+    dither: Dither,
+) -> Vec<u8> {
+    let (width, height) = (width as usize, height as usize);
+    let mut output = Vec::with_capacity(width * height * 2);
+
+    match dither {
+        Dither::None => {
+            for pixel in pixels.chunks_exact(channels).take(width * height) {
+                PixelFormat::Rgb565Le.encode([pixel[0], pixel[1], pixel[2]], &mut output);
+            }
+        }
+        Dither::Ordered => {
+            for row in 0..height {
+                let by = (origin.1 as usize + row) % 4;
+                for col in 0..width {
+                    let bx = (origin.0 as usize + col) % 4;
+                    let pixel = &pixels[(row * width + col) * channels..];
+
+                    let mut rgb = [0u8; 3];
+                    for channel in 0..3 {
+                        // Scale the threshold to the step size of the channel
+                        let step = 1i16 << DROPPED_BITS[channel];
+                        let offset = BAYER_4X4[by][bx] * step / 16;
+                        rgb[channel] =
+                            quantize(pixel[channel] as i16 + offset, DROPPED_BITS[channel]);
+                    }
+                    PixelFormat::Rgb565Le.encode(rgb, &mut output);
+                }
+            }
+        }
+        Dither::FloydSteinberg => {
+            // Error carried into the current and next row, with a pixel of slack each side
+            let mut current = vec![[0i16; 3]; width + 2];
+            let mut next = vec![[0i16; 3]; width + 2];
+
+            for row in 0..height {
+                for col in 0..width {
+                    let pixel = &pixels[(row * width + col) * channels..];
+
+                    let mut rgb = [0u8; 3];
+                    for channel in 0..3 {
+                        let wanted = pixel[channel] as i16 + current[col + 1][channel] / 16;
+                        rgb[channel] = quantize(wanted, DROPPED_BITS[channel]);
+
+                        let error = wanted.clamp(0, 255) - rgb[channel] as i16;
+                        current[col + 2][channel] += error * 7;
+                        next[col][channel] += error * 3;
+                        next[col + 1][channel] += error * 5;
+                        next[col + 2][channel] += error;
+                    }
+                    PixelFormat::Rgb565Le.encode(rgb, &mut output);
+                }
+
+                std::mem::swap(&mut current, &mut next);
+                next.fill([0; 3]);
+            }
+        }
+    }
+
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The 8-bit channels a little-endian RGB565 pixel stands for.
+    fn channels(rgb565: &[u8]) -> [u8; 3] {
+        let value = u16::from_le_bytes([rgb565[0], rgb565[1]]);
+        [
+            ((value >> 11) << 3) as u8,
+            (((value >> 5) & 0x3F) << 2) as u8,
+            ((value & 0x1F) << 3) as u8,
+        ]
+    }
+
+    /// Mean red level of a converted block.
+    fn mean_red(rgb565: &[u8]) -> f64 {
+        let total: u32 = rgb565.chunks(2).map(|p| channels(p)[0] as u32).sum();
+        total as f64 / (rgb565.len() / 2) as f64
+    }
+
+    #[test]
+    fn plain_conversion_drops_the_low_bits() {
+        let pixels = [255, 0, 0, 0, 255, 0, 0, 0, 255, 0x87, 0x87, 0x87];
+        let output = to_rgb565(&pixels, 3, 4, 1, (0, 0), Dither::None);
+        assert_eq!(output, vec![0x00, 0xF8, 0xE0, 0x07, 0x1F, 0x00, 0x30, 0x84]);
+    }
+
+    #[test]
+    fn alpha_is_ignored() {
+        let rgba = [10, 20, 30, 0, 40, 50, 60, 255];
+        let rgb = [10, 20, 30, 40, 50, 60];
+        assert_eq!(
+            to_rgb565(&rgba, 4, 2, 1, (0, 0), Dither::None),
+            to_rgb565(&rgb, 3, 2, 1, (0, 0), Dither::None)
+        );
+    }
+
+    #[test]
+    fn dithering_keeps_the_mean_level() {
+        // 100 falls between the red levels 96 and 104
+        let pixels = [100u8, 0, 0].repeat(16 * 16);
+        assert_eq!(
+            mean_red(&to_rgb565(&pixels, 3, 16, 16, (0, 0), Dither::None)),
+            96.0
+        );
+
+        for dither in [Dither::Ordered, Dither::FloydSteinberg] {
+            let mean = mean_red(&to_rgb565(&pixels, 3, 16, 16, (0, 0), dither));
+            assert!((mean - 100.0).abs() < 1.0, "{:?} gave {}", dither, mean);
+        }
+    }
+
+    #[test]
+    fn ordered_dithering_lines_up_across_blocks() {
+        let pixels: Vec<u8> = (0..8 * 4 * 3).map(|i| (i * 7 % 256) as u8).collect();
+        let whole = to_rgb565(&pixels, 3, 8, 4, (0, 0), Dither::Ordered);
+
+        // The right half, converted on its own at its place on the screen
+        let right: Vec<u8> = pixels
+            .chunks(8 * 3)
+            .flat_map(|row| row[4 * 3..].to_vec())
+            .collect();
+        let block = to_rgb565(&right, 3, 4, 4, (4, 0), Dither::Ordered);
+
+        let expected: Vec<u8> = whole
+            .chunks(8 * 2)
+            .flat_map(|row| row[4 * 2..].to_vec())
+            .collect();
+        assert_eq!(block, expected);
+    }
+}
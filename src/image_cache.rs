@@ -0,0 +1,371 @@
+use crate::image_extensions::{Scaling, scale_image};
+use reqwest::StatusCode;
+use reqwest::header::{ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::SystemTime;
+
+/// Size a cache is trimmed back to unless told otherwise.
+pub const DEFAULT_MAX_BYTES: u64 = 64 * 1024 * 1024;
+
+/// What the server said about a downloaded image, kept next to it.
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    url: String,
+    etag: Option<String>,
+    last_modified: Option<String>,
+}
+
+/// Images downloaded by URL, kept on disk between runs.
+///
+/// Each URL is stored as the original download plus any decoded and resized bitmaps
+/// made from it. Cached copies are revalidated with the server's ETag or Last-Modified
+/// date on every load, and used as they are when the server can't be reached. Files
+/// used least recently are deleted once the directory grows past its size limit.
+pub struct ImageCache {
+    dir: PathBuf,
+    max_bytes: u64,
+    client: reqwest::Client,
+}
+
+impl ImageCache {
+    pub fn new<P: Into<PathBuf>>(dir: P, max_bytes: u64) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+
+        Ok(ImageCache {
+            dir,
+            max_bytes,
+            client: reqwest::Client::new(),
+        })
+    }
+
+    /// Where images are cached unless told otherwise: `gibmon` under `$XDG_CACHE_HOME`,
+    /// or under `~/.cache` when that isn't set.
+    pub fn default_dir() -> Option<PathBuf> {
+        let base = match std::env::var_os("XDG_CACHE_HOME") {
+            Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+            _ => PathBuf::from(std::env::var_os("HOME")?).join(".cache"),
+        };
+        Some(base.join("gibmon"))
+    }
+
+    /// Downloads the image at `url` and scales it to the target size as RGBA. The image
+    /// is only downloaded and decoded again when it changed on the server.
+    pub async fn load_rgba(
+        &self,
+        url: &str,
+        target_width: u32,
+        target_height: u32,
+        scaling: Scaling,
+    ) -> Result<Vec<u8>, Box<dyn Error>> {
+        let key = cache_key(url);
+        self.refresh(url, &key).await?;
+
+        let bitmap_path = self.dir.join(format!(
+            "{}-{}x{}-{:?}-{:?}.rgba",
+            key, target_width, target_height, scaling.mode, scaling.filter
+        ));
+        let expected_len = target_width as usize * target_height as usize * 4;
+        if let Ok(bitmap) = fs::read(&bitmap_path)
+            && bitmap.len() == expected_len
+        {
+            touch(&bitmap_path);
+            return Ok(bitmap);
+        }
+
+        let original_path = self.original_path(&key);
+        let img = image::load_from_memory(&fs::read(&original_path)?)?;
+        touch(&original_path);
+        let bitmap = scale_image(&img, target_width, target_height, scaling).into_raw();
+
+        write_atomic(&bitmap_path, &bitmap)?;
+        self.evict(&key)?;
+        Ok(bitmap)
+    }
+
+    /// Makes sure the original download for `url` is on disk and current. A stale copy is
+    /// kept when the server can't be reached.
+    async fn refresh(&self, url: &str, key: &str) -> Result<(), Box<dyn Error>> {
+        let entry_path = self.dir.join(format!("{}.yaml", key));
+        let cached = fs::read_to_string(&entry_path)
+            .ok()
+            .and_then(|yaml| serde_yaml::from_str::<Entry>(&yaml).ok())
+            .filter(|entry| entry.url == url && self.original_path(key).exists());
+
+        let mut request = self.client.get(url);
+        if let Some(entry) = &cached {
+            if let Some(etag) = &entry.etag {
+                request = request.header(IF_NONE_MATCH, etag);
+            }
+            if let Some(last_modified) = &entry.last_modified {
+                request = request.header(IF_MODIFIED_SINCE, last_modified);
+            }
+        }
+
+        let response = match request
+            .send()
+            .await
+            .and_then(|resp| resp.error_for_status())
+        {
+            Ok(response) => response,
+            // Offline, the cached copy will do
+            Err(_) if cached.is_some() => return Ok(()),
+            Err(err) => return Err(err.into()),
+        };
+        if response.status() == StatusCode::NOT_MODIFIED {
+            return Ok(());
+        }
+
+        let header = |name| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(String::from)
+        };
+        let entry = Entry {
+            url: url.to_string(),
+            etag: header(ETAG),
+            last_modified: header(LAST_MODIFIED),
+        };
+        let bytes = response.bytes().await?;
+
+        // Bitmaps made from the old download are out of date
+        self.remove_bitmaps(key)?;
+        write_atomic(&self.original_path(key), &bytes)?;
+        write_atomic(&entry_path, serde_yaml::to_string(&entry)?.as_bytes())?;
+        self.evict(key)?;
+        Ok(())
+    }
+
+    fn original_path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{}.img", key))
+    }
+
+    fn remove_bitmaps(&self, key: &str) -> io::Result<()> {
+        let prefix = format!("{}-", key);
+        for file in fs::read_dir(&self.dir)? {
+            let file = file?;
+            if file.file_name().to_string_lossy().starts_with(&prefix) {
+                fs::remove_file(file.path())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Deletes the least recently used images until the cache fits in `max_bytes`. An
+    /// image goes together with its entry and bitmaps, which are no use without it. The
+    /// image under `keep` is being loaded and stays, even if it alone is over the limit.
+    fn evict(&self, keep: &str) -> io::Result<()> {
+        let mut images: HashMap<String, (SystemTime, u64, Vec<PathBuf>)> = HashMap::new();
+        let mut kept = 0;
+        for file in fs::read_dir(&self.dir)? {
+            let file = file?;
+            let metadata = file.metadata()?;
+            if !metadata.is_file() {
+                continue;
+            }
+
+            let name = file.file_name().to_string_lossy().into_owned();
+            // Someone else's write in progress
+            if name.ends_with(".tmp") {
+                continue;
+            }
+            let key = name
+                .split(['-', '.'])
+                .next()
+                .unwrap_or_default()
+                .to_string();
+            if key == keep {
+                kept += metadata.len();
+                continue;
+            }
+            let used = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+
+            let image = images
+                .entry(key)
+                .or_insert((SystemTime::UNIX_EPOCH, 0, Vec::new()));
+            image.0 = image.0.max(used);
+            image.1 += metadata.len();
+            image.2.push(file.path());
+        }
+
+        let mut total: u64 = kept + images.values().map(|(_, len, _)| len).sum::<u64>();
+        let mut images: Vec<_> = images.into_values().collect();
+        images.sort();
+        for (_, len, paths) in images {
+            if total <= self.max_bytes {
+                break;
+            }
+            for path in paths {
+                fs::remove_file(path)?;
+            }
+            total -= len;
+        }
+        Ok(())
+    }
+}
+
+/// File name stem for a URL: its 64-bit FNV-1a hash, which stays the same between builds.
+fn cache_key(url: &str) -> String {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in url.bytes() {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    format!("{:016x}", hash)
+}
+
+/// Marks a file as just used, so eviction keeps it longer.
+fn touch(path: &Path) {
+    if let Ok(file) = File::options().append(true).open(path) {
+        let _ = file.set_modified(SystemTime::now());
+    }
+}
+
+/// Writes through a temporary file, so a crash never leaves half a file behind. Every
+/// write gets its own temporary file, so loads of the same image at once can't mix.
+fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+    static WRITES: AtomicU64 = AtomicU64::new(0);
+
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let count = WRITES.fetch_add(1, Ordering::Relaxed);
+    // Hidden behind a dot, so it never shares a prefix with an image's files
+    let temp = path.with_file_name(format!(".{}.{}-{}.tmp", name, process::id(), count));
+    fs::write(&temp, data)?;
+    fs::rename(&temp, path).inspect_err(|_| {
+        let _ = fs::remove_file(&temp);
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image_extensions::ScaleMode;
+    use image::{ImageFormat, Rgba, RgbaImage};
+    use std::io::Cursor;
+    use std::time::Duration;
+
+    /// An empty cache directory of its own for each test.
+    fn cache(name: &str, max_bytes: u64) -> ImageCache {
+        let dir = std::env::temp_dir().join(format!("gibmon-cache-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        ImageCache::new(dir, max_bytes).unwrap()
+    }
+
+    /// Writes a file last used `age` seconds ago.
+    fn write_aged(cache: &ImageCache, name: &str, len: usize, age: u64) {
+        let path = cache.dir.join(name);
+        fs::write(&path, vec![0; len]).unwrap();
+        let file = File::options().append(true).open(&path).unwrap();
+        file.set_modified(SystemTime::now() - Duration::from_secs(age))
+            .unwrap();
+    }
+
+    fn files(cache: &ImageCache) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(&cache.dir)
+            .unwrap()
+            .map(|file| file.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn keys_are_stable_and_distinct() {
+        assert_eq!(cache_key(""), "cbf29ce484222325");
+        assert_eq!(cache_key("a"), "af63dc4c8601ec8c");
+        assert_ne!(
+            cache_key("https://example.com/a.png"),
+            cache_key("https://example.com/b.png")
+        );
+    }
+
+    #[test]
+    fn least_recently_used_images_go_first() {
+        let cache = cache("evict", 250);
+        write_aged(&cache, "old.img", 100, 300);
+        write_aged(&cache, "old-8x8-Fit-Nearest.rgba", 50, 300);
+        write_aged(&cache, "mid.img", 100, 200);
+        write_aged(&cache, "new.img", 100, 100);
+
+        cache.evict("none").unwrap();
+        // The old image went together with its bitmap
+        assert_eq!(files(&cache), vec!["mid.img", "new.img"]);
+        fs::remove_dir_all(&cache.dir).unwrap();
+    }
+
+    #[test]
+    fn the_image_being_loaded_is_never_evicted() {
+        let cache = cache("keep", 100);
+        write_aged(&cache, "big.img", 150, 300);
+        write_aged(&cache, "big-8x8-Fit-Nearest.rgba", 50, 300);
+        write_aged(&cache, "other.img", 10, 100);
+        write_aged(&cache, ".other.img.1-0.tmp", 1000, 100);
+
+        cache.evict("big").unwrap();
+        // Still over the limit, but only other images and nothing mid-write can go
+        assert_eq!(
+            files(&cache),
+            vec![".other.img.1-0.tmp", "big-8x8-Fit-Nearest.rgba", "big.img"]
+        );
+        fs::remove_dir_all(&cache.dir).unwrap();
+    }
+
+    #[test]
+    fn atomic_writes_leave_no_temporary_files() {
+        let cache = cache("write", DEFAULT_MAX_BYTES);
+        let path = cache.dir.join("key.img");
+        write_atomic(&path, b"first").unwrap();
+        write_atomic(&path, b"second").unwrap();
+
+        assert_eq!(fs::read(&path).unwrap(), b"second");
+        assert_eq!(files(&cache), vec!["key.img"]);
+        fs::remove_dir_all(&cache.dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn cached_copies_are_used_when_offline() {
+        let cache = cache("offline", DEFAULT_MAX_BYTES);
+        // Nothing listens on the discard port, so revalidating fails straight away
+        let url = "http://127.0.0.1:9/cover.png";
+        let key = cache_key(url);
+
+        let mut png = Vec::new();
+        RgbaImage::from_pixel(2, 2, Rgba([1, 2, 3, 255]))
+            .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+            .unwrap();
+        fs::write(cache.original_path(&key), png).unwrap();
+        let entry = Entry {
+            url: url.to_string(),
+            etag: Some("\"v1\"".to_string()),
+            last_modified: None,
+        };
+        fs::write(
+            cache.dir.join(format!("{}.yaml", key)),
+            serde_yaml::to_string(&entry).unwrap(),
+        )
+        .unwrap();
+
+        let bitmap = cache
+            .load_rgba(url, 4, 4, Scaling::new(ScaleMode::Stretch))
+            .await
+            .unwrap();
+        assert_eq!(bitmap, [1, 2, 3, 255].repeat(16));
+
+        fs::remove_dir_all(&cache.dir).unwrap();
+        assert!(
+            cache
+                .load_rgba(url, 4, 4, Scaling::new(ScaleMode::Stretch))
+                .await
+                .is_err()
+        );
+    }
+}
@@ -1,61 +1,153 @@
+use crate::dither::{Dither, to_rgb565};
 use ab_glyph::{FontRef, PxScale};
-use image::imageops::{FilterType, resize};
-use image::{self, ImageBuffer, Rgb, RgbImage, Rgba};
+use image::imageops::{FilterType, replace, resize, tile};
+use image::{self, DynamicImage, ImageBuffer, Pixel, Rgb, RgbImage, Rgba, RgbaImage};
 use imageproc::drawing::{draw_filled_rect_mut, draw_text_mut, text_size};
 use imageproc::rect::Rect;
 use reqwest;
 use std::error::Error;
 use std::path::Path;
 
-fn convert_to_rgb565(
-    img: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+/// How a picture is made to fit a target size with a different aspect ratio.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ScaleMode {
+    /// Scale to fit inside the target, leaving bars either side.
+    Fit,
+    /// Scale to cover the target, cropping the overflow equally from both sides.
+    Fill,
+    /// Scale each axis separately to match the target exactly. Distorts the picture.
+    #[default]
+    Stretch,
+    /// Keep the original size, centered, cropped or surrounded by bars.
+    Center,
+    /// Keep the original size, repeated from the top left corner.
+    Tile,
+}
+
+impl ScaleMode {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "fit" => Some(ScaleMode::Fit),
+            "fill" => Some(ScaleMode::Fill),
+            "stretch" => Some(ScaleMode::Stretch),
+            "center" => Some(ScaleMode::Center),
+            "tile" => Some(ScaleMode::Tile),
+            _ => None,
+        }
+    }
+}
+
+/// How pictures are resized when loaded. Bars left by `Fit` and `Center` are transparent
+/// in RGBA output and black in RGB565 output.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Scaling {
+    pub mode: ScaleMode,
+    /// Resampling filter, unused by `Center` and `Tile`.
+    pub filter: FilterType,
+}
+
+impl Scaling {
+    pub fn new(mode: ScaleMode) -> Self {
+        Scaling {
+            mode,
+            filter: FilterType::Lanczos3,
+        }
+    }
+}
+
+impl Default for Scaling {
+    fn default() -> Self {
+        Scaling::new(ScaleMode::default())
+    }
+}
+
+/// Resizes a decoded picture to the target size as RGBA.
+pub fn scale_image(
+    img: &DynamicImage,
     target_width: u32,
     target_height: u32,
-) -> Vec<u8> {
-    let mut rgb565_data = Vec::with_capacity((target_width * target_height * 2) as usize);
-    for pixel in img.pixels() {
-        let r = (pixel[0] as u16) >> 3; // 5 bits
-        let g = (pixel[1] as u16) >> 2; // 6 bits
-        let b = (pixel[2] as u16) >> 3; // 5 bits
-
-        let rgb565 = (r << 11) | (g << 5) | b;
-        rgb565_data.push((rgb565 & 0xFF) as u8); // Low byte
-        rgb565_data.push((rgb565 >> 8) as u8); // High byte
+    scaling: Scaling,
+) -> RgbaImage {
+    let mut canvas = RgbaImage::new(target_width, target_height);
+
+    match scaling.mode {
+        ScaleMode::Stretch => {
+            canvas = resize(&img.to_rgba8(), target_width, target_height, scaling.filter)
+        }
+        ScaleMode::Fill => {
+            canvas = img
+                .resize_to_fill(target_width, target_height, scaling.filter)
+                .to_rgba8()
+        }
+        ScaleMode::Fit => {
+            let scaled = img.resize(target_width, target_height, scaling.filter);
+            center_on(&mut canvas, &scaled.to_rgba8());
+        }
+        ScaleMode::Center => center_on(&mut canvas, &img.to_rgba8()),
+        ScaleMode::Tile => tile(&mut canvas, &img.to_rgba8()),
     }
-    rgb565_data
+
+    canvas
+}
+
+/// Copies `img` into the middle of `canvas`, cropping whatever falls outside.
+fn center_on(canvas: &mut RgbaImage, img: &RgbaImage) {
+    let x = (canvas.width() as i64 - img.width() as i64) / 2;
+    let y = (canvas.height() as i64 - img.height() as i64) / 2;
+    replace(canvas, img, x, y);
+}
+
+/// Converts an RGB or RGBA picture to RGB565. Bars left by scaling are zeroed pixels, so
+/// they come out black.
+fn convert_to_rgb565<P: Pixel<Subpixel = u8>>(
+    img: &ImageBuffer<P, Vec<u8>>,
+    dither: Dither,
+) -> Vec<u8> {
+    let channels = P::CHANNEL_COUNT as usize;
+    to_rgb565(
+        img.as_raw(),
+        channels,
+        img.width(),
+        img.height(),
+        (0, 0),
+        dither,
+    )
 }
 
 pub fn load_png_to_rgb565<P: AsRef<Path>>(
     path: P,
     target_width: u32,
     target_height: u32,
+    scaling: Scaling,
+    dither: Dither,
 ) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
     // Load the PNG image
-    let img = image::open(path)?.to_rgb8();
+    let img = image::open(path)?;
 
     // Resize the image
-    let resized_img =
-        image::imageops::resize(&img, target_width, target_height, image::imageops::Lanczos3);
+    let resized_img = scale_image(&img, target_width, target_height, scaling);
 
-    Ok(convert_to_rgb565(&resized_img, target_width, target_height))
+    Ok(convert_to_rgb565(&resized_img, dither))
 }
 
 pub async fn load_image_from_url_to_rgb565(
     url: &str,
     target_width: u32,
     target_height: u32,
+    scaling: Scaling,
+    dither: Dither,
 ) -> Result<Vec<u8>, Box<dyn Error>> {
     // Fetch the image bytes from the URL using a blocking request
     let resp = reqwest::get(url).await?.error_for_status()?;
     let bytes = resp.bytes().await?;
 
     // Decode the image from memory (this automatically handles PNG, JPEG, etc.)
-    let img = image::load_from_memory(&bytes)?.to_rgb8();
+    let img = image::load_from_memory(&bytes)?;
 
     // Resize the image
-    let resized_img = resize(&img, target_width, target_height, FilterType::Lanczos3);
+    let resized_img = scale_image(&img, target_width, target_height, scaling);
 
-    Ok(convert_to_rgb565(&resized_img, target_width, target_height))
+    Ok(convert_to_rgb565(&resized_img, dither))
 }
 
 pub fn create_playing_bar_to_rgb565(
@@ -86,7 +178,8 @@ pub fn create_playing_bar_to_rgb565(
         Rgb([255, 255, 255]),
     );
 
-    Ok(convert_to_rgb565(&img, target_width, target_height))
+    // Flat colours only, nothing to dither
+    Ok(convert_to_rgb565(&img, Dither::None))
 }
 
 pub fn create_text_image(
@@ -118,23 +211,84 @@ pub fn create_text_image(
     let (w, h) = text_size(scale, &font, text);
     println!("Text size: {}x{}", w, h);
 
-    Ok(convert_to_rgb565(&img, target_width, target_height))
+    Ok(convert_to_rgb565(&img, Dither::None))
 }
 
-pub async fn load_image_from_url_to_rgba(
-    url: &str,
-    target_width: u32,
-    target_height: u32,
-) -> Result<Vec<u8>, Box<dyn Error>> {
-    // Fetch the image bytes from the URL using a blocking request
-    let resp = reqwest::get(url).await?.error_for_status()?;
-    let bytes = resp.bytes().await?;
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    // Decode the image from memory (this automatically handles PNG, JPEG, etc.)
-    let img = image::load_from_memory(&bytes)?.to_rgba8();
+    /// A `width` x `height` opaque picture, red on the left half and blue on the right.
+    fn halves(width: u32, height: u32) -> DynamicImage {
+        DynamicImage::ImageRgba8(RgbaImage::from_fn(width, height, |x, _| {
+            if x < width / 2 {
+                Rgba([255, 0, 0, 255])
+            } else {
+                Rgba([0, 0, 255, 255])
+            }
+        }))
+    }
 
-    // Resize the image
-    let resized_img = resize(&img, target_width, target_height, FilterType::Lanczos3);
+    fn scale(img: &DynamicImage, width: u32, height: u32, mode: ScaleMode) -> RgbaImage {
+        let scaling = Scaling {
+            mode,
+            filter: FilterType::Nearest,
+        };
+        scale_image(img, width, height, scaling)
+    }
+
+    #[test]
+    fn stretch_is_the_default() {
+        assert_eq!(Scaling::default().mode, ScaleMode::Stretch);
+        assert_eq!(ScaleMode::from_name("center"), Some(ScaleMode::Center));
+        assert_eq!(ScaleMode::from_name("zoom"), None);
+    }
 
-    Ok(resized_img.to_vec())
+    #[test]
+    fn stretch_and_fill_cover_the_target() {
+        let img = halves(4, 2);
+
+        let stretched = scale(&img, 8, 8, ScaleMode::Stretch);
+        assert_eq!(stretched.get_pixel(0, 7), &Rgba([255, 0, 0, 255]));
+        assert_eq!(stretched.get_pixel(7, 0), &Rgba([0, 0, 255, 255]));
+
+        // Scaled to 16x8, then the middle 8 columns kept
+        let filled = scale(&img, 8, 8, ScaleMode::Fill);
+        assert!(filled.pixels().all(|pixel| pixel[3] == 255));
+        assert_eq!(filled.get_pixel(0, 0), &Rgba([255, 0, 0, 255]));
+        assert_eq!(filled.get_pixel(7, 0), &Rgba([0, 0, 255, 255]));
+    }
+
+    #[test]
+    fn fit_and_center_leave_transparent_bars() {
+        let img = halves(4, 2);
+
+        let fitted = scale(&img, 8, 8, ScaleMode::Fit);
+        assert_eq!(fitted.get_pixel(0, 0)[3], 0);
+        assert_eq!(fitted.get_pixel(0, 4), &Rgba([255, 0, 0, 255]));
+        assert_eq!(fitted.get_pixel(0, 7)[3], 0);
+
+        let centered = scale(&img, 8, 4, ScaleMode::Center);
+        assert_eq!(centered.get_pixel(1, 1)[3], 0);
+        assert_eq!(centered.get_pixel(2, 1), &Rgba([255, 0, 0, 255]));
+        assert_eq!(centered.get_pixel(5, 2), &Rgba([0, 0, 255, 255]));
+        assert_eq!(centered.get_pixel(6, 2)[3], 0);
+    }
+
+    #[test]
+    fn tile_repeats_from_the_top_left() {
+        let tiled = scale(&halves(2, 2), 5, 3, ScaleMode::Tile);
+        let reds: Vec<u32> = (0..5)
+            .filter(|&x| tiled.get_pixel(x, 2)[0] == 255)
+            .collect();
+        assert_eq!(reds, vec![0, 2, 4]);
+    }
+
+    #[test]
+    fn bars_come_out_black() {
+        let fitted = scale(&halves(4, 2), 4, 4, ScaleMode::Fit);
+        let rgb565 = convert_to_rgb565(&fitted, Dither::None);
+        assert_eq!(&rgb565[..2], &[0, 0]);
+        assert_eq!(&rgb565[4 * 2..4 * 2 + 2], &[0x00, 0xF8]);
+    }
 }
@@ -1,23 +1,45 @@
+mod async_device;
 mod device;
+mod discovery;
+mod dither;
 mod gibmon_config;
+mod image_cache;
 mod image_extensions;
+mod manager;
 mod packets;
+mod pixel_format;
+mod protocol;
+mod simulator;
 mod spotify;
+mod trace;
+mod transport;
 mod r#virtual;
 
-use crate::device::{Device, Orientation};
+use crate::async_device::AsyncDevice;
+use crate::device::{Device, DeviceConfig, DeviceError, Orientation};
+use crate::discovery::{UsbId, find_port};
+use crate::dither::Dither;
 use crate::gibmon_config::load_config;
+use crate::image_cache::{DEFAULT_MAX_BYTES, ImageCache};
 use crate::image_extensions::{
-    create_playing_bar_to_rgb565, create_text_image, load_image_from_url_to_rgb565,
-    load_image_from_url_to_rgba,
+    ScaleMode, Scaling, create_playing_bar_to_rgb565, create_text_image,
+    load_image_from_url_to_rgb565,
 };
+use crate::manager::DeviceManager;
+use crate::protocol::Revision;
+use crate::protocol::rev_a::RevA;
+use crate::simulator::{Simulator, SimulatorTransport};
 use crate::spotify::{fetch_currently_playing, fetch_spotify_token};
-use crate::r#virtual::display::Display;
+use crate::trace::{RecordingTransport, dump, read_trace, replay};
+use crate::transport::Transport;
 use crate::r#virtual::image::Image;
+use crate::r#virtual::layer::Layer;
+use crate::r#virtual::transform::{Rotation, Transform};
 use image::{GenericImageView, Pixel};
 use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::Duration;
+use tokio::task;
 
 #[tokio::main]
 async fn main() {
@@ -28,45 +50,269 @@ async fn main() {
     // let text = Text::new(&display);
     // text.update_text("New text value");
 
-    let device = Arc::new(Mutex::new(
-        Device::new().expect("Could not connect to COM4 port"),
-    ));
+    // Use an explicit port (a serial path, usb:vid:pid, tcp:host:port or file:path) if
+    // one is given. Otherwise every known display is driven, but the simulator and trace
+    // replays stand in for one: a 5" display if there is one, else a 3.5" one
+    let port = std::env::var("GIBMON_PORT").ok();
+    let mut device_config = match &port {
+        Some(port_name) => DeviceConfig::with_port(port_name),
+        None if find_port(&UsbId::TURING_5).is_ok() => DeviceConfig::turing_5(),
+        None => DeviceConfig::default(),
+    };
+    // Record all serial traffic for debugging
+    device_config.trace = std::env::var("GIBMON_TRACE").ok().map(Into::into);
 
-    // Perform some basic device setup
-    {
-        let mut dev = device.lock().unwrap();
-        dev.set_brightness(80).expect("Could not set brightness");
+    // Render into a simulated panel instead of real hardware when asked to
+    let simulator_output = std::env::var("GIBMON_SIMULATOR").ok();
+    let simulator = Arc::new(Mutex::new(Simulator::new(
+        device_config.width,
+        device_config.height,
+    )));
 
-        dev.set_orientation(Orientation::ReverseLandscape)
-            .expect("Could not set orientation");
+    // `gibmon trace dump <file>` and `gibmon trace replay <file>` work on recorded traffic
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    match args.as_slice() {
+        [] => {}
+        ["trace", command @ ("dump" | "replay"), path] => {
+            run_trace_command(command, path, &device_config, &simulator);
+            if let Some(output) = &simulator_output {
+                save_simulator_output(&simulator.lock().unwrap(), output);
+            }
+            return;
+        }
+        _ => {
+            eprintln!("Usage: gibmon [trace dump|replay <file>]");
+            std::process::exit(2);
+        }
     }
 
-    let display = Arc::new(Mutex::new(Display::new(device.clone())));
-
-    let image_data = load_image_from_url_to_rgba(
-        "https://i.scdn.co/image/ab67616d0000b273e9c3c16b480e1c5a84d7b188",
-        256,
-        256,
-    )
-    .await
-    .expect("Could not load image");
-    let basic_image = Image::new(112, 32, 256, 256, image_data);
-
-    let transparent_image_data = load_image_from_url_to_rgba(
-        "https://www.transparenttextures.com/patterns/brushed-alum-dark.png",
-        480,
-        320,
-    )
-    .await
-    .expect("Could not load image");
-    let transparent_image = Image::new(0, 0, 480, 320, transparent_image_data);
-
-    // Add image to display as layer
-    {
-        let mut d = display.lock().unwrap();
-        d.add_layer(1, Arc::new(Mutex::new(basic_image)));
-        d.add_layer(0, Arc::new(Mutex::new(transparent_image)));
-        d.redraw_full();
+    // Drive every display that is plugged in, or a simulated one
+    let (manager, failures) = match &simulator_output {
+        Some(_) => {
+            let transport = simulator_transport(&simulator, &device_config);
+            task::spawn_blocking(move || {
+                let device = Device::with_protocol(
+                    transport,
+                    Box::new(RevA::new()),
+                    device_config.width,
+                    device_config.height,
+                )?;
+                let mut manager = DeviceManager::default();
+                manager.add(device, 80, Orientation::ReverseLandscape)?;
+                Ok::<_, DeviceError>((manager, Vec::new()))
+            })
+            .await
+            .expect("Simulator setup panicked")
+            .expect("Could not start simulator")
+        }
+        None => {
+            // Without a port, drive every known model that is plugged in
+            let templates = match port {
+                Some(_) => vec![device_config],
+                None => DeviceConfig::known_models()
+                    .into_iter()
+                    .map(|config| DeviceConfig {
+                        trace: device_config.trace.clone(),
+                        ..config
+                    })
+                    .collect(),
+            };
+            task::spawn_blocking(move || {
+                DeviceManager::open_detected(&templates, 80, Orientation::ReverseLandscape)
+            })
+            .await
+            .expect("Display discovery panicked")
+            .expect("Could not find a display")
+        }
+    };
+    for (config, err) in &failures {
+        eprintln!(
+            "Could not connect to display on {:?}: {}",
+            config.device.port, err
+        );
+    }
+    if manager.is_empty() {
+        eprintln!("No display could be connected");
+        return;
+    }
+    // Panels left in a bad state by an earlier run can be restarted first
+    let reset_on_startup = std::env::var("GIBMON_RESET_ON_STARTUP").is_ok();
+    for screen in manager.screens() {
+        let device = AsyncDevice::from_shared(screen.device.clone());
+        if reset_on_startup {
+            device.reset().await.expect("Could not reset display");
+        }
+        println!("Connected to display: {:?}", device.info().await);
+    }
+
+    // Keep downloaded images around so they survive restarts and going offline
+    let cache_dir = std::env::var("GIBMON_CACHE")
+        .map(Into::into)
+        .ok()
+        .or_else(ImageCache::default_dir)
+        .unwrap_or_else(|| std::env::temp_dir().join("gibmon"));
+    let image_cache =
+        ImageCache::new(cache_dir, DEFAULT_MAX_BYTES).expect("Could not create image cache");
+
+    let album_art_url = "https://i.scdn.co/image/ab67616d0000b273e9c3c16b480e1c5a84d7b188";
+    // fit, fill, stretch, center or tile
+    let album_art_scaling = match std::env::var("GIBMON_ALBUM_ART_SCALING") {
+        Ok(name) => ScaleMode::from_name(&name)
+            .expect("GIBMON_ALBUM_ART_SCALING must be fit, fill, stretch, center or tile"),
+        Err(_) => ScaleMode::Fit,
+    };
+    let album_art_scaling = Scaling::new(album_art_scaling);
+    let image_data = image_cache
+        .load_rgba(album_art_url, 256, 256, album_art_scaling)
+        .await
+        .expect("Could not load image");
+    let transform = transform_from_env();
+    // Smooth out gradients on the 16-bit panels: none, ordered or floyd-steinberg
+    let dither = match std::env::var("GIBMON_DITHER") {
+        Ok(name) => Dither::from_name(&name)
+            .expect("GIBMON_DITHER must be none, ordered or floyd-steinberg"),
+        Err(_) => Dither::default(),
+    };
+    // Smaller tiles skip more unchanged pixels but send more bitmap commands
+    let tile_size = std::env::var("GIBMON_TILE_SIZE").ok().map(|tile_size| {
+        tile_size
+            .parse::<u32>()
+            .expect("GIBMON_TILE_SIZE must be a number")
+    });
+    // Composite on more than one core, worth it on bigger panels
+    let threads = std::env::var("GIBMON_THREADS").ok().map(|threads| {
+        threads
+            .parse::<usize>()
+            .expect("GIBMON_THREADS must be a number")
+    });
+
+    // Give every display its own copy of the layers, each tracks its own damage
+    let mut album_art = Vec::new();
+    for screen in manager.screens() {
+        let device = screen.device.clone();
+        let display = screen.display.clone();
+        let (layout_width, layout_height) = task::spawn_blocking(move || {
+            // Pictures sent straight to the panel are fitted like the album art and dithered
+            // like the layers
+            {
+                let mut dev = device.lock().unwrap();
+                dev.set_scaling(album_art_scaling);
+                dev.set_dither(dither);
+            }
+
+            let mut d = display.lock().unwrap();
+            d.set_transform(transform);
+            d.set_dither(dither);
+            if let Some(threads) = threads {
+                d.set_threads(threads);
+            }
+            if let Some(tile_size) = tile_size {
+                d.set_tile_size(tile_size);
+            }
+            d.layout_size()
+        })
+        .await
+        .expect("Display setup panicked");
+
+        // Fill the layout whichever way round it is, with the album art in the middle
+        let transparent_image_data = image_cache
+            .load_rgba(
+                "https://www.transparenttextures.com/patterns/brushed-alum-dark.png",
+                layout_width,
+                layout_height,
+                Scaling::new(ScaleMode::Tile),
+            )
+            .await
+            .expect("Could not load image");
+        let basic_image = Arc::new(Mutex::new(Image::new(
+            layout_width.saturating_sub(256) / 2,
+            layout_height.saturating_sub(256) / 2,
+            256,
+            256,
+            image_data.clone(),
+        )));
+        let transparent_image =
+            Image::new(0, 0, layout_width, layout_height, transparent_image_data);
+        album_art.push(basic_image.clone());
+
+        // Compositing and the first upload block, so keep them off the runtime
+        let display = screen.display.clone();
+        task::spawn_blocking(move || {
+            let mut d = display.lock().unwrap();
+            d.add_layer(1, basic_image);
+            d.add_layer(0, Arc::new(Mutex::new(transparent_image)));
+            d.redraw_full()?;
+            d.flush()
+        })
+        .await
+        .expect("Display update panicked")
+        .expect("Failed to update full display");
+
+        let device = AsyncDevice::from_shared(screen.device.clone());
+        println!("Streamed {}", device.stream_stats().await);
+    }
+
+    if let Some(path) = &simulator_output {
+        save_simulator_output(&simulator.lock().unwrap(), path);
+        return;
+    }
+
+    // Keep the displays up to date until interrupted
+    let render_loops = manager.run(Duration::from_secs(1), |_, display| display.redraw());
+    let mut album_art_refresh = tokio::time::interval(Duration::from_secs(60));
+    loop {
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => break,
+            _ = album_art_refresh.tick() => {
+                // Pick up a new picture if the one behind the URL has changed
+                match image_cache.load_rgba(album_art_url, 256, 256, album_art_scaling).await {
+                    Ok(image_data) => {
+                        // The render threads hold these locks while compositing
+                        let album_art = album_art.clone();
+                        let updated = task::spawn_blocking(move || {
+                            album_art.iter().try_for_each(|image| {
+                                let mut image = image.lock().unwrap();
+                                if image.get_image_data() == &image_data {
+                                    return Ok(());
+                                }
+                                image.set_image_data(image_data.clone())
+                            })
+                        })
+                        .await
+                        .expect("Album art update panicked");
+                        if let Err(err) = updated {
+                            eprintln!("Could not update album art: {}", err);
+                        }
+                    }
+                    Err(err) => eprintln!("Could not refresh album art: {}", err),
+                }
+            }
+            _ = tokio::time::sleep(Duration::from_secs(1)) => {}
+        }
+        for (index, err) in manager.take_errors() {
+            eprintln!("Display {} skipped a frame: {}", index, err);
+        }
+    }
+    manager.stop();
+    for (index, render_loop) in render_loops.into_iter().enumerate() {
+        let result = task::spawn_blocking(move || render_loop.join()).await;
+        if let Ok(Ok(Err(err))) = result {
+            eprintln!("Display {} stopped: {}", index, err);
+        }
+    }
+    for (index, screen) in manager.screens().iter().enumerate() {
+        let stats = AsyncDevice::from_shared(screen.device.clone())
+            .stream_stats()
+            .await;
+        let display = screen.display.clone();
+        let coalesced = task::spawn_blocking(move || display.lock().unwrap().coalesced())
+            .await
+            .expect("Display stats panicked");
+        println!(
+            "Display {} streamed {}, {} frames merged while it was busy",
+            index, stats, coalesced
+        );
     }
 
     // device
@@ -77,6 +323,8 @@ async fn main() {
     //     "https://i.scdn.co/image/ab67616d0000b273e9c3c16b480e1c5a84d7b188",
     //     256,
     //     256,
+    //     Scaling::new(ScaleMode::Fit),
+    //     Dither::None,
     // )
     // .await
     // .expect("Could not load image");
@@ -152,3 +400,91 @@ async fn main() {
     //     Err(err) => eprintln!("Failed to fetch currently playing track: {}", err),
     // }
 }
+
+/// Reads how to turn the layout onto the screens from `GIBMON_ROTATION` (clockwise
+/// degrees: 0, 90, 180 or 270) and `GIBMON_MIRROR` (x, y or xy).
+fn transform_from_env() -> Transform {
+    let rotation = match std::env::var("GIBMON_ROTATION") {
+        Ok(degrees) => degrees
+            .parse()
+            .ok()
+            .and_then(Rotation::from_degrees)
+            .expect("GIBMON_ROTATION must be 0, 90, 180 or 270"),
+        Err(_) => Rotation::default(),
+    };
+    let mirror = std::env::var("GIBMON_MIRROR").unwrap_or_default();
+    if !matches!(mirror.as_str(), "" | "x" | "y" | "xy") {
+        panic!("GIBMON_MIRROR must be x, y or xy");
+    }
+
+    Transform {
+        mirror_x: mirror.contains('x'),
+        mirror_y: mirror.contains('y'),
+        ..Transform::rotate(rotation)
+    }
+}
+
+/// Feeds the simulator, recording the traffic too if a trace was asked for.
+fn simulator_transport(
+    simulator: &Arc<Mutex<Simulator>>,
+    device_config: &DeviceConfig,
+) -> Box<dyn Transport> {
+    let transport = Box::new(SimulatorTransport::new(simulator.clone()));
+    match &device_config.trace {
+        Some(path) => Box::new(
+            RecordingTransport::open(transport, path, Revision::A)
+                .expect("Could not open trace file"),
+        ),
+        None => transport,
+    }
+}
+
+/// Saves what the simulated panel shows, and reports the state a picture can't show.
+fn save_simulator_output(simulator: &Simulator, path: &str) {
+    simulator
+        .save_png(path)
+        .expect("Could not save simulator output");
+    println!(
+        "Simulated panel: brightness {}%, screen {}, {} unknown commands",
+        simulator.brightness(),
+        if simulator.is_screen_on() {
+            "on"
+        } else {
+            "off"
+        },
+        simulator.unknown_commands()
+    );
+}
+
+/// Dumps a trace, or replays it into the simulator when `GIBMON_SIMULATOR` is set and to
+/// the display otherwise.
+fn run_trace_command(
+    command: &str,
+    path: &str,
+    device_config: &DeviceConfig,
+    simulator: &Arc<Mutex<Simulator>>,
+) {
+    let trace = read_trace(path).expect("Could not read trace");
+
+    match command {
+        "dump" => {
+            dump(&trace, &mut std::io::stdout().lock()).expect("Could not dump trace");
+        }
+        "replay" => {
+            let mut transport: Box<dyn Transport> = if std::env::var("GIBMON_SIMULATOR").is_ok() {
+                if trace.revision != Revision::A {
+                    eprintln!("The simulator only understands revision A traces");
+                    return;
+                }
+                Box::new(SimulatorTransport::new(simulator.clone()))
+            } else {
+                // The bare port, so the replay isn't recorded on top of the trace
+                device_config
+                    .open_port()
+                    .expect("Could not connect to display")
+            };
+            replay(&trace.records, transport.as_mut(), true).expect("Could not replay trace");
+        }
+        _ => unreachable!("trace commands are matched in main"),
+    }
+}
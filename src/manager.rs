@@ -0,0 +1,327 @@
+use crate::device::{Device, DeviceConfig, DeviceError, Orientation, PortSelector};
+use crate::discovery::{describe_ports, find_ports};
+use crate::r#virtual::display::Display;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// One screen to drive and the settings it should start with.
+#[derive(Debug, Clone)]
+pub struct ScreenConfig {
+    pub device: DeviceConfig,
+    pub brightness: u8,
+    pub orientation: Orientation,
+}
+
+/// An open screen and the display compositing onto it.
+pub struct Screen {
+    pub device: Arc<Mutex<Device>>,
+    pub display: Arc<Mutex<Display>>,
+}
+
+/// Drives several screens at once, each with its own device, display and render loop.
+#[derive(Default)]
+pub struct DeviceManager {
+    screens: Vec<Screen>,
+    running: Arc<AtomicBool>,
+    // Frames the render loops skipped, by screen index
+    errors: Arc<Mutex<Vec<(usize, DeviceError)>>>,
+}
+
+impl DeviceManager {
+    /// Opens every configured screen. Each is opened on its own, so one that fails doesn't
+    /// stop the rest; those are returned with the reason they failed.
+    pub fn open(configs: Vec<ScreenConfig>) -> (Self, Vec<(ScreenConfig, DeviceError)>) {
+        let mut manager = DeviceManager::default();
+        let mut failures = Vec::new();
+
+        for config in configs {
+            let result = Device::new(config.device.clone())
+                .and_then(|device| manager.add(device, config.brightness, config.orientation));
+            if let Err(err) = result {
+                failures.push((config, err));
+            }
+        }
+
+        (manager, failures)
+    }
+
+    /// Adds an open device as another screen, starting it at the given brightness and
+    /// orientation.
+    pub fn add(
+        &mut self,
+        mut device: Device,
+        brightness: u8,
+        orientation: Orientation,
+    ) -> Result<(), DeviceError> {
+        device.set_brightness(brightness)?;
+        device.set_orientation(orientation)?;
+
+        let device = Arc::new(Mutex::new(device));
+        let display = Arc::new(Mutex::new(Display::new(device.clone())));
+        self.screens.push(Screen { device, display });
+        Ok(())
+    }
+
+    /// Opens every port matching the USB id of any of `templates`, each with the settings
+    /// of the template it matched. Templates with no matching port are skipped; ports other
+    /// than USB ids are opened as they are.
+    pub fn open_detected(
+        templates: &[DeviceConfig],
+        brightness: u8,
+        orientation: Orientation,
+    ) -> Result<(Self, Vec<(ScreenConfig, DeviceError)>), DeviceError> {
+        let mut configs = Vec::new();
+        for template in templates {
+            let ports = match &template.port {
+                PortSelector::Usb(id) => match find_ports(id) {
+                    Ok(ports) => ports.into_iter().map(PortSelector::Path).collect(),
+                    Err(DeviceError::PortNotFound { .. }) => continue,
+                    Err(err) => return Err(err),
+                },
+                port => vec![port.clone()],
+            };
+
+            configs.extend(ports.into_iter().map(|port| ScreenConfig {
+                device: DeviceConfig {
+                    port,
+                    ..template.clone()
+                },
+                brightness,
+                orientation,
+            }));
+        }
+
+        if configs.is_empty() {
+            let wanted: Vec<String> = templates
+                .iter()
+                .map(|template| match &template.port {
+                    PortSelector::Usb(id) => id.to_string(),
+                    port => format!("{:?}", port),
+                })
+                .collect();
+            return Err(DeviceError::PortNotFound {
+                wanted: wanted.join(" or "),
+                candidates: describe_ports(),
+            });
+        }
+
+        Ok(Self::open(configs))
+    }
+
+    pub fn screens(&self) -> &[Screen] {
+        &self.screens
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.screens.is_empty()
+    }
+
+    /// Starts one render thread per screen. Each calls `render` with the screen's index
+    /// and display every `interval` until `stop` is called or `render` fails with an error
+    /// that isn't recoverable. Frames skipped over recoverable errors are reported by
+    /// `take_errors`.
+    pub fn run<F>(&self, interval: Duration, render: F) -> Vec<JoinHandle<Result<(), DeviceError>>>
+    where
+        F: Fn(usize, &mut Display) -> Result<(), DeviceError> + Send + Sync + 'static,
+    {
+        self.running.store(true, Ordering::SeqCst);
+        let render = Arc::new(render);
+
+        self.screens
+            .iter()
+            .enumerate()
+            .map(|(index, screen)| {
+                let display = screen.display.clone();
+                let running = self.running.clone();
+                let render = render.clone();
+                let errors = self.errors.clone();
+
+                thread::spawn(move || {
+                    while running.load(Ordering::SeqCst) {
+                        let started = Instant::now();
+                        match render(index, &mut display.lock().unwrap()) {
+                            Err(err) if err.is_recoverable() => {
+                                // Skip this frame, the device may be back by the next one
+                                errors.lock().unwrap().push((index, err));
+                            }
+                            result => result?,
+                        }
+                        thread::sleep(interval.saturating_sub(started.elapsed()));
+                    }
+                    Ok(())
+                })
+            })
+            .collect()
+    }
+
+    /// Returns the errors render loops skipped frames over since the last call, with the
+    /// index of the screen each came from.
+    pub fn take_errors(&self) -> Vec<(usize, DeviceError)> {
+        std::mem::take(&mut *self.errors.lock().unwrap())
+    }
+
+    /// Asks every render loop to finish after its current frame.
+    pub fn stop(&self) {
+        self.running.store(false, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::discovery::UsbId;
+    use crate::protocol::Protocol;
+    use crate::protocol::Revision;
+    use crate::protocol::rev_a::RevA;
+    use crate::transport::MemoryTransport;
+
+    fn open(memory: &MemoryTransport) -> Device {
+        let device =
+            Device::with_protocol(Box::new(memory.clone()), Box::new(RevA::new()), 4, 4).unwrap();
+        memory.take_written();
+        device
+    }
+
+    fn timeout() -> DeviceError {
+        DeviceError::Timeout(std::io::ErrorKind::TimedOut.into())
+    }
+
+    #[test]
+    fn added_screens_start_with_the_settings_given() {
+        let memory = MemoryTransport::new();
+        let mut manager = DeviceManager::default();
+        assert!(manager.is_empty());
+
+        manager
+            .add(open(&memory), 50, Orientation::Landscape)
+            .unwrap();
+
+        let mut rev_a = RevA::new();
+        let mut expected = rev_a.brightness(50).unwrap();
+        expected.extend(rev_a.orientation(Orientation::Landscape, 4, 4));
+        assert_eq!(memory.written(), expected);
+        assert_eq!(manager.screens().len(), 1);
+    }
+
+    #[test]
+    fn render_loops_skip_recoverable_errors_until_stopped() {
+        let mut manager = DeviceManager::default();
+        for _ in 0..2 {
+            manager
+                .add(open(&MemoryTransport::new()), 50, Orientation::Portrait)
+                .unwrap();
+        }
+
+        // Only the second screen fails, and only on its first frame
+        let failed = Arc::new(AtomicBool::new(false));
+        let render_failed = failed.clone();
+        let render_loops = manager.run(Duration::from_millis(1), move |index, _| {
+            if index == 1 && !render_failed.swap(true, Ordering::SeqCst) {
+                return Err(timeout());
+            }
+            Ok(())
+        });
+
+        let started = Instant::now();
+        let errors = loop {
+            let errors = manager.take_errors();
+            if !errors.is_empty() || started.elapsed() > Duration::from_secs(5) {
+                break errors;
+            }
+            thread::sleep(Duration::from_millis(1));
+        };
+        assert!(matches!(errors.as_slice(), [(1, DeviceError::Timeout(_))]));
+
+        manager.stop();
+        for render_loop in render_loops {
+            render_loop.join().unwrap().unwrap();
+        }
+        assert!(manager.take_errors().is_empty());
+    }
+
+    #[test]
+    fn render_loops_end_on_errors_that_wont_go_away() {
+        let mut manager = DeviceManager::default();
+        manager
+            .add(open(&MemoryTransport::new()), 50, Orientation::Portrait)
+            .unwrap();
+
+        let render_loops = manager.run(Duration::from_millis(1), |_, _| {
+            Err(DeviceError::InvalidArgument("bad frame".to_string()))
+        });
+
+        for render_loop in render_loops {
+            assert!(matches!(
+                render_loop.join().unwrap(),
+                Err(DeviceError::InvalidArgument(_))
+            ));
+        }
+        assert!(manager.take_errors().is_empty());
+    }
+
+    fn file_template(name: &str) -> DeviceConfig {
+        let path =
+            std::env::temp_dir().join(format!("gibmon-manager-{}-{}", name, std::process::id()));
+        DeviceConfig {
+            port: PortSelector::File(path),
+            revision: Some(Revision::A),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn every_template_with_a_port_is_opened() {
+        let absent = DeviceConfig {
+            port: PortSelector::Usb(UsbId::new(0xFFFF, 0xFFFF, Some("gibmon-test"))),
+            ..Default::default()
+        };
+        let small = file_template("small");
+        let large = DeviceConfig {
+            width: 480,
+            height: 800,
+            ..file_template("large")
+        };
+
+        let (manager, failures) = DeviceManager::open_detected(
+            &[absent, small.clone(), large.clone()],
+            50,
+            Orientation::Portrait,
+        )
+        .unwrap();
+        assert!(failures.is_empty());
+
+        // Each screen keeps the size of the template it came from
+        let sizes: Vec<(u16, u16)> = manager
+            .screens()
+            .iter()
+            .map(|screen| {
+                let device = screen.device.lock().unwrap();
+                (device.get_width(), device.get_height())
+            })
+            .collect();
+        assert_eq!(sizes, vec![(320, 480), (480, 800)]);
+
+        for template in [small, large] {
+            if let PortSelector::File(path) = template.port {
+                std::fs::remove_file(path).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn finding_nothing_is_an_error() {
+        let absent = DeviceConfig {
+            port: PortSelector::Usb(UsbId::new(0xFFFF, 0xFFFF, Some("gibmon-test"))),
+            ..Default::default()
+        };
+
+        match DeviceManager::open_detected(&[absent], 50, Orientation::Portrait) {
+            Err(DeviceError::PortNotFound { wanted, .. }) => {
+                assert!(wanted.contains("ffff:ffff"))
+            }
+            other => panic!("expected no port to be found, got {:?}", other.err()),
+        }
+    }
+}
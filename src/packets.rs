@@ -1,6 +1,8 @@
 use crate::device::Orientation;
+use std::fmt;
 
 enum PacketIds {
+    Reset,
     Hello,
     ScreenWhite,
     ScreenBlack,
@@ -14,6 +16,7 @@ enum PacketIds {
 impl PacketIds {
     fn get_id(&self) -> u8 {
         match self {
+            PacketIds::Reset => 0x65,
             PacketIds::Hello => 0xFF,
             PacketIds::ScreenWhite => 0x66,
             PacketIds::ScreenBlack => 0x67,
@@ -24,6 +27,367 @@ impl PacketIds {
             PacketIds::DisplayImage => 0xC5,
         }
     }
+
+    fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0x65 => Some(PacketIds::Reset),
+            0xFF => Some(PacketIds::Hello),
+            0x66 => Some(PacketIds::ScreenWhite),
+            0x67 => Some(PacketIds::ScreenBlack),
+            0x6C => Some(PacketIds::ScreenOff),
+            0x6D => Some(PacketIds::ScreenOn),
+            0x6E => Some(PacketIds::Brightness),
+            0x79 => Some(PacketIds::Orientation),
+            0xC5 => Some(PacketIds::DisplayImage),
+            _ => None,
+        }
+    }
+}
+
+/// Length of every command except orientation.
+pub const PACKET_SIZE: usize = 6;
+/// Length of the orientation command.
+pub const ORIENTATION_PACKET_SIZE: usize = 16;
+
+/// A single revision A command, as sent over the wire.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Packet {
+    /// Restarts the panel, which then drops off the USB bus for a few seconds.
+    Reset,
+    Hello,
+    ScreenWhite,
+    ScreenBlack,
+    ScreenOff,
+    ScreenOn,
+    /// Raw backlight level as sent to the panel: 0 is brightest, 255 is darkest.
+    Brightness {
+        level_absolute: u16,
+    },
+    Orientation {
+        orientation: Orientation,
+        width: u16,
+        height: u16,
+    },
+    /// Starts a bitmap upload to the inclusive rectangle (x, y)-(ex, ey). It is followed by
+    /// `payload_len()` bytes of RGB565 pixel data.
+    DisplayImage {
+        x: u16,
+        y: u16,
+        ex: u16,
+        ey: u16,
+    },
+}
+
+/// Coordinates are packed into 10 bits each.
+const COORDINATE_LIMIT: u32 = 1 << 10;
+
+/// Why a command couldn't be built from the arguments given.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EncodeError {
+    ZeroSize {
+        width: u16,
+        height: u16,
+    },
+    /// The region runs past the screen, or past what the command can address.
+    OutOfBounds {
+        x: u16,
+        y: u16,
+        width: u16,
+        height: u16,
+        limit_width: u32,
+        limit_height: u32,
+    },
+    /// The pixel data doesn't match the size of the region.
+    PayloadLength {
+        expected: usize,
+        actual: usize,
+    },
+}
+
+impl fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EncodeError::ZeroSize { width, height } => {
+                write!(f, "region is empty ({}x{})", width, height)
+            }
+            EncodeError::OutOfBounds {
+                x,
+                y,
+                width,
+                height,
+                limit_width,
+                limit_height,
+            } => write!(
+                f,
+                "region {}x{} at ({}, {}) doesn't fit in {}x{}",
+                width, height, x, y, limit_width, limit_height
+            ),
+            EncodeError::PayloadLength { expected, actual } => write!(
+                f,
+                "expected {} bytes of pixel data, got {}",
+                expected, actual
+            ),
+        }
+    }
+}
+
+impl std::error::Error for EncodeError {}
+
+/// Checks that a region is non-empty and lies within `limit_width` x `limit_height`,
+/// returning its inclusive bottom-right corner.
+pub fn region_end(
+    x: u16,
+    y: u16,
+    width: u16,
+    height: u16,
+    limit_width: u32,
+    limit_height: u32,
+) -> Result<(u16, u16), EncodeError> {
+    if width == 0 || height == 0 {
+        return Err(EncodeError::ZeroSize { width, height });
+    }
+
+    if x as u32 + width as u32 > limit_width || y as u32 + height as u32 > limit_height {
+        return Err(EncodeError::OutOfBounds {
+            x,
+            y,
+            width,
+            height,
+            limit_width,
+            limit_height,
+        });
+    }
+
+    // Can't overflow: the region ends at or before the limit, which is at most 65536
+    let ex = (x as u32 + width as u32 - 1) as u16;
+    let ey = (y as u32 + height as u32 - 1) as u16;
+    Ok((ex, ey))
+}
+
+/// Checks that `len` bytes is exactly one region's worth of pixels.
+pub fn check_payload(
+    width: u16,
+    height: u16,
+    bytes_per_pixel: usize,
+    len: usize,
+) -> Result<(), EncodeError> {
+    let expected = width as usize * height as usize * bytes_per_pixel;
+    if len != expected {
+        return Err(EncodeError::PayloadLength {
+            expected,
+            actual: len,
+        });
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    /// At least this many bytes are needed to decode the next packet.
+    Incomplete(usize),
+    UnknownCommand(u8),
+    InvalidOrientation(u8),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::Incomplete(needed) => write!(f, "need {} bytes", needed),
+            DecodeError::UnknownCommand(id) => write!(f, "unknown command 0x{:02X}", id),
+            DecodeError::InvalidOrientation(id) => write!(f, "invalid orientation {}", id),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+impl Packet {
+    /// A brightness command for `level` on the 1-100 scale used by `Device`.
+    pub fn brightness(level: u8) -> Result<Packet, String> {
+        if !(1..=100).contains(&level) {
+            return Err("Brightness level must be between 0 and 100".to_string());
+        }
+
+        // Map brightness level (0-100) to display range (0-255)
+        let level_absolute = 255 - ((level as f32 / 100.0) * 255.0).round() as u16;
+        Ok(Packet::Brightness { level_absolute })
+    }
+
+    /// Starts a bitmap upload to a region, which must be non-empty and within what the
+    /// 10-bit coordinates can address.
+    pub fn display_image(x: u16, y: u16, width: u16, height: u16) -> Result<Packet, EncodeError> {
+        let (ex, ey) = region_end(x, y, width, height, COORDINATE_LIMIT, COORDINATE_LIMIT)?;
+        Ok(Packet::DisplayImage { x, y, ex, ey })
+    }
+
+    /// Fails for coordinates that don't fit in their 10 bits.
+    pub fn encode(&self) -> Result<Vec<u8>, EncodeError> {
+        let packet = match self {
+            Packet::Reset => create_reset_packet().to_vec(),
+            Packet::Hello => create_hello_packet().to_vec(),
+            Packet::ScreenWhite => create_screen_white_packet().to_vec(),
+            Packet::ScreenBlack => create_screen_black_packet().to_vec(),
+            Packet::ScreenOff => create_screen_off_packet().to_vec(),
+            Packet::ScreenOn => create_screen_on_packet().to_vec(),
+            Packet::Brightness { level_absolute } => {
+                let mut packet = [0u8; 6];
+                packet[0] = (level_absolute >> 2) as u8;
+                packet[1] = ((level_absolute & 0x03) << 6) as u8;
+                packet[5] = PacketIds::Brightness.get_id();
+                packet.to_vec()
+            }
+            Packet::Orientation {
+                orientation,
+                width,
+                height,
+            } => create_orientation_packet(*orientation, *width, *height).to_vec(),
+            Packet::DisplayImage { x, y, ex, ey } => {
+                let (x, y, ex, ey) = (*x, *y, *ex, *ey);
+                // Each coordinate only has 10 bits
+                if [x, y, ex, ey]
+                    .iter()
+                    .any(|&coordinate| coordinate as u32 >= COORDINATE_LIMIT)
+                {
+                    return Err(EncodeError::OutOfBounds {
+                        x,
+                        y,
+                        width: ex.saturating_sub(x).saturating_add(1),
+                        height: ey.saturating_sub(y).saturating_add(1),
+                        limit_width: COORDINATE_LIMIT,
+                        limit_height: COORDINATE_LIMIT,
+                    });
+                }
+
+                let mut packet = [0u8; 6];
+                packet[..5].copy_from_slice(&pack_coordinates(x, y, ex, ey));
+                packet[5] = PacketIds::DisplayImage.get_id();
+                packet.to_vec()
+            }
+        };
+
+        Ok(packet)
+    }
+
+    /// Decodes the packet at the start of `data`, returning it with the number of bytes
+    /// consumed. Pixel data following a `DisplayImage` is not consumed.
+    pub fn decode(data: &[u8]) -> Result<(Packet, usize), DecodeError> {
+        if data.len() < PACKET_SIZE {
+            return Err(DecodeError::Incomplete(PACKET_SIZE));
+        }
+
+        let id = data[5];
+        let kind = PacketIds::from_id(id).ok_or(DecodeError::UnknownCommand(id))?;
+        let (x, y, ex, ey) = unpack_coordinates(&data[..5]);
+
+        let packet = match kind {
+            PacketIds::Reset => Packet::Reset,
+            PacketIds::Hello => Packet::Hello,
+            PacketIds::ScreenWhite => Packet::ScreenWhite,
+            PacketIds::ScreenBlack => Packet::ScreenBlack,
+            PacketIds::ScreenOff => Packet::ScreenOff,
+            PacketIds::ScreenOn => Packet::ScreenOn,
+            PacketIds::Brightness => Packet::Brightness {
+                level_absolute: ((data[0] as u16) << 2) | (data[1] as u16 >> 6),
+            },
+            PacketIds::DisplayImage => Packet::DisplayImage { x, y, ex, ey },
+            PacketIds::Orientation => {
+                if data.len() < ORIENTATION_PACKET_SIZE {
+                    return Err(DecodeError::Incomplete(ORIENTATION_PACKET_SIZE));
+                }
+
+                let orientation_id = data[6].wrapping_sub(100);
+                let orientation = Orientation::from_id(orientation_id)
+                    .ok_or(DecodeError::InvalidOrientation(data[6]))?;
+
+                let packet = Packet::Orientation {
+                    orientation,
+                    width: u16::from_be_bytes([data[7], data[8]]),
+                    height: u16::from_be_bytes([data[9], data[10]]),
+                };
+                return Ok((packet, ORIENTATION_PACKET_SIZE));
+            }
+        };
+
+        Ok((packet, PACKET_SIZE))
+    }
+
+    /// Number of pixel data bytes that follow this packet on the wire.
+    pub fn payload_len(&self) -> usize {
+        match self {
+            Packet::DisplayImage { x, y, ex, ey } if ex >= x && ey >= y => {
+                (*ex - *x + 1) as usize * (*ey - *y + 1) as usize * 2
+            }
+            _ => 0,
+        }
+    }
+
+    /// Converts a raw brightness level back to the 0-100 scale used by `Device`.
+    pub fn brightness_percent(level_absolute: u16) -> u8 {
+        100 - ((level_absolute.min(255) as f32 / 255.0) * 100.0).round() as u8
+    }
+}
+
+impl fmt::Display for Packet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Packet::Reset => write!(f, "Reset"),
+            Packet::Hello => write!(f, "Hello"),
+            Packet::ScreenWhite => write!(f, "ScreenWhite"),
+            Packet::ScreenBlack => write!(f, "ScreenBlack"),
+            Packet::ScreenOff => write!(f, "ScreenOff"),
+            Packet::ScreenOn => write!(f, "ScreenOn"),
+            Packet::Brightness { level_absolute } => write!(
+                f,
+                "Brightness {}% (raw {})",
+                Packet::brightness_percent(*level_absolute),
+                level_absolute
+            ),
+            Packet::Orientation {
+                orientation,
+                width,
+                height,
+            } => write!(f, "Orientation {:?} {}x{}", orientation, width, height),
+            Packet::DisplayImage { x, y, ex, ey } => write!(
+                f,
+                "DisplayImage ({}, {})-({}, {}) payload {} bytes",
+                x,
+                y,
+                ex,
+                ey,
+                self.payload_len()
+            ),
+        }
+    }
+}
+
+/// Packs an inclusive rectangle into the 5-byte coordinate header shared by all commands.
+fn pack_coordinates(x: u16, y: u16, ex: u16, ey: u16) -> [u8; 5] {
+    [
+        (x >> 2) as u8,
+        (((x & 3) << 6) + (y >> 4)) as u8,
+        (((y & 15) << 4) + (ex >> 6)) as u8,
+        (((ex & 63) << 2) + (ey >> 8)) as u8,
+        (ey & 255) as u8,
+    ]
+}
+
+fn unpack_coordinates(header: &[u8]) -> (u16, u16, u16, u16) {
+    let (b0, b1, b2, b3, b4) = (
+        header[0] as u16,
+        header[1] as u16,
+        header[2] as u16,
+        header[3] as u16,
+        header[4] as u16,
+    );
+    let x = (b0 << 2) | (b1 >> 6);
+    let y = ((b1 & 0x3F) << 4) | (b2 >> 4);
+    let ex = ((b2 & 0x0F) << 6) | (b3 >> 2);
+    let ey = ((b3 & 0x03) << 8) | b4;
+    (x, y, ex, ey)
+}
+
+pub fn create_reset_packet() -> [u8; 6] {
+    [0x00, 0x00, 0x00, 0x00, 0x00, PacketIds::Reset.get_id()]
 }
 
 pub fn create_hello_packet() -> [u8; 6] {
@@ -60,24 +424,6 @@ pub fn create_screen_off_packet() -> [u8; 6] {
     [0x00, 0x00, 0x00, 0x00, 0x00, PacketIds::ScreenOff.get_id()]
 }
 
-pub fn create_screen_brightness_packet(level: u8) -> Result<[u8; 6], String> {
-    // Validate brightness level
-    if level > 100 || level < 1 {
-        return Err("Brightness level must be between 0 and 100".to_string());
-    }
-
-    // Map brightness level (0-100) to display range (0-255)
-    let level_absolute = 255 - ((level as f32 / 100.0) * 255.0).round() as u8;
-
-    // Create the brightness packet
-    let mut packet = [0u8; 6];
-    packet[0] = (level_absolute >> 2);
-    packet[1] = ((level_absolute & 0x03) << 6);
-    packet[5] = PacketIds::Brightness.get_id();
-
-    Ok(packet)
-}
-
 pub fn create_orientation_packet(orientation: Orientation, width: u16, height: u16) -> [u8; 16] {
     // Initialize coordinates (x, y, ex, ey)
     let x: u16 = 0;
@@ -87,11 +433,7 @@ pub fn create_orientation_packet(orientation: Orientation, width: u16, height: u
 
     // Create the packet
     let mut packet = [0u8; 16];
-    packet[0] = (x >> 2) as u8;
-    packet[1] = (((x & 3) << 6) + (y >> 4)) as u8;
-    packet[2] = (((y & 15) << 4) + (ex >> 6)) as u8;
-    packet[3] = (((ex & 63) << 2) + (ey >> 8)) as u8;
-    packet[4] = (ey & 255) as u8;
+    packet[..5].copy_from_slice(&pack_coordinates(x, y, ex, ey));
     packet[5] = PacketIds::Orientation.get_id();
     packet[6] = orientation.get_id() + 100;
     packet[7] = (width >> 8) as u8;
@@ -99,26 +441,154 @@ pub fn create_orientation_packet(orientation: Orientation, width: u16, height: u
     packet[9] = (height >> 8) as u8;
     packet[10] = (height & 255) as u8;
 
-    // The rest of the packet is padding (0x00)
-    for i in 11..16 {
-        packet[i] = 0x00;
-    }
+    // The rest of the packet is padding, already zeroed
 
     packet
 }
 
-pub fn create_display_image_packet(x: u16, y: u16, width: u16, height: u16) -> [u8; 6] {
-    // Create a 6-byte buffer
-    let mut packet = [0u8; 6];
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    let (ex, ey) = (x + width - 1, y + height - 1);
+    fn round_trip(packet: Packet) {
+        let encoded = packet.encode().unwrap();
+        assert_eq!(Packet::decode(&encoded), Ok((packet, encoded.len())));
+    }
 
-    packet[0] = (x >> 2) as u8;
-    packet[1] = (((x & 3) << 6) + (y >> 4)) as u8;
-    packet[2] = (((y & 15) << 4) + (ex >> 6)) as u8;
-    packet[3] = (((ex & 63) << 2) + (ey >> 8)) as u8;
-    packet[4] = (ey & 255) as u8;
-    packet[5] = PacketIds::DisplayImage.get_id();
+    #[test]
+    fn simple_commands_round_trip() {
+        for packet in [
+            Packet::Reset,
+            Packet::Hello,
+            Packet::ScreenWhite,
+            Packet::ScreenBlack,
+            Packet::ScreenOff,
+            Packet::ScreenOn,
+        ] {
+            round_trip(packet);
+        }
+    }
 
-    packet
+    #[test]
+    fn brightness_round_trips() {
+        for level_absolute in [0, 1, 127, 255] {
+            round_trip(Packet::Brightness { level_absolute });
+        }
+    }
+
+    #[test]
+    fn brightness_packet_matches_percentage() {
+        for level in [1, 50, 100] {
+            match Packet::brightness(level) {
+                Ok(Packet::Brightness { level_absolute }) => {
+                    assert_eq!(Packet::brightness_percent(level_absolute), level)
+                }
+                other => panic!("built {:?}", other),
+            }
+        }
+        assert!(Packet::brightness(0).is_err());
+        assert!(Packet::brightness(101).is_err());
+    }
+
+    #[test]
+    fn orientation_round_trips() {
+        for orientation in [
+            Orientation::Portrait,
+            Orientation::ReversePortrait,
+            Orientation::Landscape,
+            Orientation::ReverseLandscape,
+        ] {
+            let packet = Packet::Orientation {
+                orientation,
+                width: 320,
+                height: 480,
+            };
+            assert_eq!(packet.encode().unwrap().len(), ORIENTATION_PACKET_SIZE);
+            round_trip(packet);
+        }
+    }
+
+    #[test]
+    fn display_image_coordinates_round_trip() {
+        for (x, y, ex, ey) in [(0, 0, 0, 0), (1, 2, 318, 479), (1023, 1023, 1023, 1023)] {
+            round_trip(Packet::DisplayImage { x, y, ex, ey });
+        }
+    }
+
+    #[test]
+    fn display_image_packet_covers_region() {
+        let packet = Packet::display_image(10, 20, 30, 40).unwrap();
+        let (decoded, _) = Packet::decode(&packet.encode().unwrap()).unwrap();
+        assert_eq!(
+            decoded,
+            Packet::DisplayImage {
+                x: 10,
+                y: 20,
+                ex: 39,
+                ey: 59
+            }
+        );
+        assert_eq!(decoded.payload_len(), 30 * 40 * 2);
+    }
+
+    #[test]
+    fn display_image_packet_rejects_bad_regions() {
+        assert_eq!(
+            Packet::display_image(0, 0, 0, 10),
+            Err(EncodeError::ZeroSize {
+                width: 0,
+                height: 10
+            })
+        );
+        assert!(matches!(
+            Packet::display_image(1000, 0, 100, 10),
+            Err(EncodeError::OutOfBounds { .. })
+        ));
+        assert!(Packet::display_image(1023, 1023, 1, 1).is_ok());
+        assert_eq!(region_end(65535, 0, 1, 1, 1 << 16, 1 << 16), Ok((65535, 0)));
+    }
+
+    #[test]
+    fn coordinates_that_dont_fit_are_rejected() {
+        for (x, y, ex, ey) in [(0, 0, 1024, 0), (0, 0, 0, 1024), (1024, 0, 1030, 0)] {
+            assert!(matches!(
+                Packet::DisplayImage { x, y, ex, ey }.encode(),
+                Err(EncodeError::OutOfBounds {
+                    limit_width: 1024,
+                    limit_height: 1024,
+                    ..
+                })
+            ));
+        }
+    }
+
+    #[test]
+    fn decode_reports_short_and_unknown_input() {
+        assert_eq!(
+            Packet::decode(&[0; 3]),
+            Err(DecodeError::Incomplete(PACKET_SIZE))
+        );
+        assert_eq!(
+            Packet::decode(&[0, 0, 0, 0, 0, 0x12]),
+            Err(DecodeError::UnknownCommand(0x12))
+        );
+
+        let orientation = create_orientation_packet(Orientation::Portrait, 320, 480);
+        assert_eq!(
+            Packet::decode(&orientation[..PACKET_SIZE + 1]),
+            Err(DecodeError::Incomplete(ORIENTATION_PACKET_SIZE))
+        );
+    }
+
+    #[test]
+    fn payload_is_checked_against_region() {
+        assert_eq!(check_payload(2, 3, 2, 12), Ok(()));
+        assert_eq!(
+            check_payload(2, 3, 2, 11),
+            Err(EncodeError::PayloadLength {
+                expected: 12,
+                actual: 11
+            })
+        );
+    }
 }
@@ -0,0 +1,184 @@
+/// Ways panels expect pixels to be laid out on the wire.
+///
+/// Frames are composited and stored as `Rgb565Le`; each protocol converts to whatever
+/// its panel wants when the pixels are sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PixelFormat {
+    /// Red in the top 5 bits, low byte first.
+    #[default]
+    Rgb565Le,
+    /// Red in the top 5 bits, high byte first.
+    Rgb565Be,
+    /// Blue in the top 5 bits, low byte first.
+    Bgr565Le,
+    Rgb888,
+    Bgr888,
+}
+
+impl PixelFormat {
+    pub fn bytes_per_pixel(&self) -> usize {
+        match self {
+            PixelFormat::Rgb565Le | PixelFormat::Rgb565Be | PixelFormat::Bgr565Le => 2,
+            PixelFormat::Rgb888 | PixelFormat::Bgr888 => 3,
+        }
+    }
+
+    /// Appends one pixel given as 8-bit red, green and blue.
+    pub fn encode(&self, [r, g, b]: [u8; 3], output: &mut Vec<u8>) {
+        match self {
+            PixelFormat::Rgb565Le => output.extend_from_slice(&pack_565(r, g, b).to_le_bytes()),
+            PixelFormat::Rgb565Be => output.extend_from_slice(&pack_565(r, g, b).to_be_bytes()),
+            // Red and blue trade places
+            PixelFormat::Bgr565Le => output.extend_from_slice(&pack_565(b, g, r).to_le_bytes()),
+            PixelFormat::Rgb888 => output.extend_from_slice(&[r, g, b]),
+            PixelFormat::Bgr888 => output.extend_from_slice(&[b, g, r]),
+        }
+    }
+
+    /// Reads one pixel back as 8-bit red, green and blue. 5 and 6 bit channels are
+    /// widened so that full intensity stays 255.
+    pub fn decode(&self, bytes: &[u8]) -> [u8; 3] {
+        match self {
+            PixelFormat::Rgb565Le => unpack_565(u16::from_le_bytes([bytes[0], bytes[1]])),
+            PixelFormat::Rgb565Be => unpack_565(u16::from_be_bytes([bytes[0], bytes[1]])),
+            PixelFormat::Bgr565Le => {
+                let [b, g, r] = unpack_565(u16::from_le_bytes([bytes[0], bytes[1]]));
+                [r, g, b]
+            }
+            PixelFormat::Rgb888 => [bytes[0], bytes[1], bytes[2]],
+            PixelFormat::Bgr888 => [bytes[2], bytes[1], bytes[0]],
+        }
+    }
+
+    /// Re-encodes pixels in this format as `target`.
+    pub fn convert(&self, data: &[u8], target: PixelFormat) -> Vec<u8> {
+        let count = data.len() / self.bytes_per_pixel();
+        let mut output = Vec::with_capacity(count * target.bytes_per_pixel());
+
+        match (self, target) {
+            (from, to) if *from == to => output.extend_from_slice(data),
+            // Just a byte swap
+            (PixelFormat::Rgb565Le, PixelFormat::Rgb565Be)
+            | (PixelFormat::Rgb565Be, PixelFormat::Rgb565Le) => {
+                for pixel in data.chunks_exact(2) {
+                    output.extend_from_slice(&[pixel[1], pixel[0]]);
+                }
+            }
+            _ => {
+                for pixel in data.chunks_exact(self.bytes_per_pixel()) {
+                    target.encode(self.decode(pixel), &mut output);
+                }
+            }
+        }
+
+        output
+    }
+}
+
+/// Packs 8-bit channels into 5-6-5 bits, red on top.
+fn pack_565(red: u8, green: u8, blue: u8) -> u16 {
+    ((red as u16 >> 3) << 11) | ((green as u16 >> 2) << 5) | (blue as u16 >> 3)
+}
+
+fn unpack_565(value: u16) -> [u8; 3] {
+    let red = ((value >> 11) & 0x1F) as u8;
+    let green = ((value >> 5) & 0x3F) as u8;
+    let blue = (value & 0x1F) as u8;
+    [
+        (red << 3) | (red >> 2),
+        (green << 2) | (green >> 4),
+        (blue << 3) | (blue >> 2),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn byte_order_is_swapped() {
+        let le = [0x00, 0xF8, 0x1F, 0x00];
+        let be = PixelFormat::Rgb565Le.convert(&le, PixelFormat::Rgb565Be);
+        assert_eq!(be, vec![0xF8, 0x00, 0x00, 0x1F]);
+        assert_eq!(
+            PixelFormat::Rgb565Be.convert(&be, PixelFormat::Rgb565Le),
+            le
+        );
+    }
+
+    #[test]
+    fn full_intensity_widens_to_255() {
+        // Red, green, blue and white
+        let le = [0x00, 0xF8, 0xE0, 0x07, 0x1F, 0x00, 0xFF, 0xFF];
+        let bgr = PixelFormat::Rgb565Le.convert(&le, PixelFormat::Bgr888);
+        assert_eq!(bgr, vec![0, 0, 255, 0, 255, 0, 255, 0, 0, 255, 255, 255]);
+    }
+
+    #[test]
+    fn rgb565_survives_a_round_trip_through_bgr888() {
+        let le: Vec<u8> = (0..=u16::MAX)
+            .step_by(97)
+            .flat_map(u16::to_le_bytes)
+            .collect();
+        let bgr = PixelFormat::Rgb565Le.convert(&le, PixelFormat::Bgr888);
+        assert_eq!(bgr.len(), le.len() / 2 * 3);
+        assert_eq!(PixelFormat::Bgr888.convert(&bgr, PixelFormat::Rgb565Le), le);
+    }
+
+    #[test]
+    fn bgr565_swaps_red_and_blue() {
+        // Red, then blue
+        let rgb = [0x00, 0xF8, 0x1F, 0x00];
+        let bgr = PixelFormat::Rgb565Le.convert(&rgb, PixelFormat::Bgr565Le);
+        assert_eq!(bgr, vec![0x1F, 0x00, 0x00, 0xF8]);
+        assert_eq!(
+            PixelFormat::Bgr565Le.convert(&bgr, PixelFormat::Rgb565Le),
+            rgb
+        );
+    }
+
+    #[test]
+    fn rgb565_survives_a_round_trip_through_bgr565() {
+        let le: Vec<u8> = (0..=u16::MAX)
+            .step_by(97)
+            .flat_map(u16::to_le_bytes)
+            .collect();
+        let bgr = PixelFormat::Rgb565Le.convert(&le, PixelFormat::Bgr565Le);
+        assert_eq!(bgr.len(), le.len());
+        assert_eq!(
+            PixelFormat::Bgr565Le.convert(&bgr, PixelFormat::Rgb565Le),
+            le
+        );
+    }
+
+    #[test]
+    fn rgb888_keeps_channel_order() {
+        let le = [0x00, 0xF8, 0xE0, 0x07, 0x1F, 0x00];
+        let rgb = PixelFormat::Rgb565Le.convert(&le, PixelFormat::Rgb888);
+        assert_eq!(rgb, vec![255, 0, 0, 0, 255, 0, 0, 0, 255]);
+        assert_eq!(
+            PixelFormat::Rgb888.convert(&rgb, PixelFormat::Bgr888),
+            vec![0, 0, 255, 0, 255, 0, 255, 0, 0]
+        );
+    }
+
+    #[test]
+    fn rgb565_survives_a_round_trip_through_rgb888() {
+        let le: Vec<u8> = (0..=u16::MAX)
+            .step_by(97)
+            .flat_map(u16::to_le_bytes)
+            .collect();
+        let rgb = PixelFormat::Rgb565Le.convert(&le, PixelFormat::Rgb888);
+        assert_eq!(rgb.len(), le.len() / 2 * 3);
+        assert_eq!(PixelFormat::Rgb888.convert(&rgb, PixelFormat::Rgb565Le), le);
+    }
+
+    #[test]
+    fn same_format_is_copied() {
+        let data = [1, 2, 3, 4, 5, 6];
+        assert_eq!(
+            PixelFormat::Bgr888.convert(&data, PixelFormat::Bgr888),
+            data
+        );
+    }
+}
@@ -0,0 +1,286 @@
+pub mod rev_a;
+pub mod rev_b;
+pub mod rev_c;
+
+use crate::device::Orientation;
+use crate::packets::{DecodeError, EncodeError, PACKET_SIZE, Packet};
+use crate::pixel_format::PixelFormat;
+use crate::transport::Transport;
+use std::borrow::Cow;
+use std::io;
+use std::time::Duration;
+
+/// Hardware revisions of the Turing / XuanFang family of USB displays.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Revision {
+    /// Turing Smart Screen 3.5", 6-byte commands.
+    A,
+    /// XuanFang 3.5", 10-byte framed commands.
+    B,
+    /// Turing Smart Screen 5", commands padded to 250-byte blocks.
+    C,
+}
+
+impl Revision {
+    pub fn get_id(&self) -> u8 {
+        match self {
+            Revision::A => 0,
+            Revision::B => 1,
+            Revision::C => 2,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(Revision::A),
+            1 => Some(Revision::B),
+            2 => Some(Revision::C),
+            _ => None,
+        }
+    }
+}
+
+/// A command picked back out of a recorded byte stream.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecodedCommand {
+    pub description: String,
+    /// Bytes the command takes up, not counting its pixel data.
+    pub len: usize,
+    /// Bytes of pixel data that follow it.
+    pub payload_len: usize,
+}
+
+/// Decodes the command at the start of `data` as the given revision sends it.
+pub fn decode_command(revision: Revision, data: &[u8]) -> Result<DecodedCommand, DecodeError> {
+    match revision {
+        Revision::A => {
+            let (packet, len) = Packet::decode(data)?;
+            Ok(DecodedCommand {
+                description: packet.to_string(),
+                len,
+                payload_len: packet.payload_len(),
+            })
+        }
+        Revision::B => rev_b::decode_command(data),
+        Revision::C => rev_c::decode_command(data),
+    }
+}
+
+/// Length of the shortest command in a revision, how far to skip past one that can't be
+/// decoded.
+pub fn command_len(revision: Revision) -> usize {
+    match revision {
+        Revision::A => PACKET_SIZE,
+        Revision::B => rev_b::PACKET_SIZE,
+        Revision::C => rev_c::BLOCK_SIZE,
+    }
+}
+
+/// What a panel said about itself in reply to hello.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceInfo {
+    pub revision: Revision,
+    /// Model or firmware identifier reported by the panel, empty if it did not say.
+    pub sub_revision: String,
+    /// Native (portrait) resolution, if the reply identifies the model.
+    pub resolution: Option<(u16, u16)>,
+}
+
+/// Builds the byte stream for one hardware revision.
+///
+/// `Device` keeps the panel-independent state and asks its protocol for the bytes to
+/// send, so every revision sits behind the same `Device` API.
+pub trait Protocol: Send {
+    fn revision(&self) -> Revision;
+
+    fn hello(&self) -> Vec<u8>;
+
+    /// Most bytes the panel sends back after `hello`.
+    fn hello_reply_len(&self) -> usize;
+
+    /// Identifies the panel from whatever it sent back after `hello`, which may be empty.
+    /// Fails if the reply shows the panel speaks some other protocol.
+    fn hello_reply(&mut self, reply: &[u8]) -> Result<DeviceInfo, String>;
+
+    fn screen_on(&mut self) -> Vec<u8>;
+
+    fn screen_off(&mut self) -> Vec<u8>;
+
+    /// A command the panel answers, if it has one, to check it is still listening.
+    fn ping(&self) -> Option<Vec<u8>> {
+        None
+    }
+
+    /// Restarts the panel, if it has a command for it.
+    fn reset(&self) -> Option<Vec<u8>> {
+        None
+    }
+
+    /// Fills the screen with black or white, if the panel has a command for it.
+    fn screen_fill(&self, _white: bool) -> Option<Vec<u8>> {
+        None
+    }
+
+    /// Sets the backlight, `level` is 0-100.
+    fn brightness(&mut self, level: u8) -> Result<Vec<u8>, String>;
+
+    /// `width` and `height` are the screen size in the new orientation.
+    fn orientation(&mut self, orientation: Orientation, width: u16, height: u16) -> Vec<u8>;
+
+    /// Starts a bitmap upload to the given region, failing if the panel can't address it.
+    fn display_image(
+        &mut self,
+        x: u16,
+        y: u16,
+        width: u16,
+        height: u16,
+    ) -> Result<Vec<u8>, EncodeError>;
+
+    /// How the panel wants its pixels.
+    fn pixel_format(&self) -> PixelFormat {
+        PixelFormat::Rgb565Le
+    }
+
+    /// Converts little-endian RGB565 pixels for the region into what the panel expects.
+    fn encode_pixels<'a>(
+        &self,
+        _x: u16,
+        _y: u16,
+        _width: u16,
+        _height: u16,
+        rgb565: &'a [u8],
+    ) -> Cow<'a, [u8]> {
+        match self.pixel_format() {
+            PixelFormat::Rgb565Le => Cow::Borrowed(rgb565),
+            format => Cow::Owned(PixelFormat::Rgb565Le.convert(rgb565, format)),
+        }
+    }
+
+    /// Sent after the pixel data of every bitmap upload.
+    fn display_image_end(&mut self) -> Vec<u8> {
+        Vec::new()
+    }
+}
+
+/// How long to wait for a panel to answer its hello.
+pub const PROBE_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Works out which protocol the panel speaks by sending a revision B hello, then a
+/// revision C one.
+///
+/// A revision B panel answers the first with a framed reply and a revision C panel
+/// answers the second with its model name. A revision A panel stays silent and reads
+/// both as unknown 6-byte commands. Each hello leaves it 4 bytes into a command, so two
+/// padding bytes after each put it back on a command boundary.
+pub fn detect(transport: &mut dyn Transport) -> io::Result<Box<dyn Protocol>> {
+    transport.set_timeout(PROBE_TIMEOUT)?;
+    transport.write_all(&rev_b::RevB::hello_packet())?;
+
+    let reply = read_reply(transport, rev_b::HELLO_REPLY_SIZE)?;
+    if let Some(protocol) = rev_b::RevB::from_hello_reply(&reply) {
+        return Ok(Box::new(protocol));
+    }
+    transport.write_all(&[0x00, 0x00])?;
+
+    transport.write_all(&rev_c::RevC::hello_packet())?;
+    let reply = read_reply(transport, rev_c::HELLO_REPLY_SIZE)?;
+    if rev_c::RevC::is_hello_reply(&reply) {
+        return Ok(Box::new(rev_c::RevC::new()));
+    }
+    transport.write_all(&[0x00, 0x00])?;
+
+    Ok(Box::new(rev_a::RevA::new()))
+}
+
+/// Reads up to `len` bytes, stopping early if the other end goes quiet.
+pub fn read_reply(transport: &mut dyn Transport, len: usize) -> io::Result<Vec<u8>> {
+    let mut reply = vec![0u8; len];
+    let mut received = 0;
+
+    while received < len {
+        match transport.read(&mut reply[received..]) {
+            Ok(0) => break,
+            Ok(count) => received += count,
+            Err(err) if err.kind() == io::ErrorKind::TimedOut => break,
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+            Err(err) => return Err(err),
+        }
+    }
+
+    reply.truncate(received);
+    Ok(reply)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+
+    /// Answers the hello of one revision and remembers everything written.
+    struct Panel {
+        revision: Revision,
+        written: Vec<u8>,
+        replies: VecDeque<u8>,
+    }
+
+    impl Panel {
+        fn new(revision: Revision) -> Self {
+            Panel {
+                revision,
+                written: Vec::new(),
+                replies: VecDeque::new(),
+            }
+        }
+    }
+
+    impl Transport for Panel {
+        fn write_all(&mut self, data: &[u8]) -> io::Result<()> {
+            self.written.extend_from_slice(data);
+            if self.revision == Revision::B && data == rev_b::RevB::hello_packet() {
+                let mut reply = rev_b::RevB::hello_packet();
+                reply[6..8].copy_from_slice(&[0x0A, 0x12]);
+                self.replies.extend(reply);
+            }
+            if self.revision == Revision::C && data == rev_c::RevC::hello_packet() {
+                self.replies.extend(b"chs_5inch.dev1_rom1.87");
+            }
+            Ok(())
+        }
+
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let count = buf.len().min(self.replies.len());
+            for (dst, src) in buf.iter_mut().zip(self.replies.drain(..count)) {
+                *dst = src;
+            }
+            Ok(count)
+        }
+    }
+
+    #[test]
+    fn detects_each_revision() {
+        for revision in [Revision::A, Revision::B, Revision::C] {
+            let mut panel = Panel::new(revision);
+            let protocol = detect(&mut panel).unwrap();
+            assert_eq!(protocol.revision(), revision);
+        }
+    }
+
+    #[test]
+    fn revision_a_is_left_on_a_command_boundary() {
+        let mut panel = Panel::new(Revision::A);
+        detect(&mut panel).unwrap();
+        assert_eq!(panel.written.len() % crate::packets::PACKET_SIZE, 0);
+    }
+
+    #[test]
+    fn revision_c_reply_is_not_taken_for_a_revision_a_model() {
+        let mut protocol = rev_a::RevA::new();
+        let info = protocol.hello_reply(b"chs_5inch.dev1_rom1.87").unwrap();
+        assert_eq!(info.resolution, None);
+
+        let info = rev_c::RevC::new()
+            .hello_reply(b"chs_5inch.dev1_rom1.87")
+            .unwrap();
+        assert_eq!(info.resolution, Some((480, 800)));
+    }
+}
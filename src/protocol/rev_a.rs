@@ -0,0 +1,111 @@
+use crate::device::Orientation;
+use crate::packets::{EncodeError, Packet};
+use crate::protocol::{DeviceInfo, Protocol, Revision};
+
+/// Models reported in reply to hello, with their native resolution.
+const MODELS: [(&str, (u16, u16)); 3] = [
+    ("USBMONITOR35", (320, 480)),
+    ("USBMONITOR50", (480, 800)),
+    ("USBMONITOR70", (600, 1024)),
+];
+
+/// Long enough for the longest model name.
+const HELLO_REPLY_SIZE: usize = 12;
+
+/// Encodes a command with no coordinates, which can't fail.
+fn encode(packet: Packet) -> Vec<u8> {
+    packet
+        .encode()
+        .expect("only bitmap regions can be out of range")
+}
+
+/// Turing Smart Screen revision A, built on the packets in `packets.rs`.
+pub struct RevA {
+    // Only newer firmware answers hello, so only it can be pinged
+    answers_hello: bool,
+}
+
+impl RevA {
+    pub fn new() -> Self {
+        RevA {
+            answers_hello: false,
+        }
+    }
+}
+
+impl Protocol for RevA {
+    fn revision(&self) -> Revision {
+        Revision::A
+    }
+
+    fn hello(&self) -> Vec<u8> {
+        encode(Packet::Hello)
+    }
+
+    fn hello_reply_len(&self) -> usize {
+        HELLO_REPLY_SIZE
+    }
+
+    fn hello_reply(&mut self, reply: &[u8]) -> Result<DeviceInfo, String> {
+        // Older firmware stays silent, newer firmware answers with a model name
+        let reply = String::from_utf8_lossy(reply);
+        let sub_revision = reply.trim_matches(char::from(0)).trim().to_string();
+        self.answers_hello = !sub_revision.is_empty();
+        let resolution = MODELS
+            .iter()
+            .find(|(name, _)| sub_revision.starts_with(name))
+            .map(|(_, resolution)| *resolution);
+
+        Ok(DeviceInfo {
+            revision: Revision::A,
+            sub_revision,
+            resolution,
+        })
+    }
+
+    fn screen_on(&mut self) -> Vec<u8> {
+        encode(Packet::ScreenOn)
+    }
+
+    fn screen_off(&mut self) -> Vec<u8> {
+        encode(Packet::ScreenOff)
+    }
+
+    fn ping(&self) -> Option<Vec<u8>> {
+        self.answers_hello.then(|| encode(Packet::Hello))
+    }
+
+    fn reset(&self) -> Option<Vec<u8>> {
+        Some(encode(Packet::Reset))
+    }
+
+    fn screen_fill(&self, white: bool) -> Option<Vec<u8>> {
+        if white {
+            Some(encode(Packet::ScreenWhite))
+        } else {
+            Some(encode(Packet::ScreenBlack))
+        }
+    }
+
+    fn brightness(&mut self, level: u8) -> Result<Vec<u8>, String> {
+        Packet::brightness(level).map(encode)
+    }
+
+    fn orientation(&mut self, orientation: Orientation, width: u16, height: u16) -> Vec<u8> {
+        encode(Packet::Orientation {
+            orientation,
+            width,
+            height,
+        })
+    }
+
+    fn display_image(
+        &mut self,
+        x: u16,
+        y: u16,
+        width: u16,
+        height: u16,
+    ) -> Result<Vec<u8>, EncodeError> {
+        Packet::display_image(x, y, width, height)?.encode()
+    }
+}
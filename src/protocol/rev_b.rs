@@ -0,0 +1,364 @@
+use crate::device::Orientation;
+use crate::packets::{DecodeError, EncodeError, region_end};
+use crate::pixel_format::PixelFormat;
+use crate::protocol::{DecodedCommand, DeviceInfo, Protocol, Revision};
+use std::borrow::Cow;
+
+enum Command {
+    Hello,
+    SetOrientation,
+    DisplayBitmap,
+    SetBrightness,
+}
+
+impl Command {
+    fn get_id(&self) -> u8 {
+        match self {
+            Command::Hello => 0xCA,
+            Command::SetOrientation => 0xCB,
+            Command::DisplayBitmap => 0xCC,
+            Command::SetBrightness => 0xCE,
+        }
+    }
+
+    fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0xCA => Some(Command::Hello),
+            0xCB => Some(Command::SetOrientation),
+            0xCC => Some(Command::DisplayBitmap),
+            0xCE => Some(Command::SetBrightness),
+            _ => None,
+        }
+    }
+}
+
+pub const PACKET_SIZE: usize = 10;
+pub const HELLO_REPLY_SIZE: usize = 10;
+
+/// Sub-revisions reported in bytes 6 and 7 of the hello reply.
+const SUB_REVISION_A01: u16 = 0x0A01;
+const SUB_REVISION_A02: u16 = 0x0A02;
+const SUB_REVISION_A11: u16 = 0x0A11;
+const SUB_REVISION_A12: u16 = 0x0A12;
+
+/// Frames a command: the id, eight bytes of payload, then the id again.
+fn create_packet(command: Command, payload: &[u8]) -> Vec<u8> {
+    let mut packet = vec![0u8; PACKET_SIZE];
+    packet[0] = command.get_id();
+    packet[1..1 + payload.len()].copy_from_slice(payload);
+    packet[PACKET_SIZE - 1] = command.get_id();
+    packet
+}
+
+/// Decodes the framed command at the start of `data`.
+pub fn decode_command(data: &[u8]) -> Result<DecodedCommand, DecodeError> {
+    if data.len() < PACKET_SIZE {
+        return Err(DecodeError::Incomplete(PACKET_SIZE));
+    }
+
+    let id = data[0];
+    let command = Command::from_id(id)
+        .filter(|_| data[PACKET_SIZE - 1] == id)
+        .ok_or(DecodeError::UnknownCommand(id))?;
+    let word = |index: usize| u16::from_be_bytes([data[index], data[index + 1]]);
+
+    let (description, payload_len) = match command {
+        Command::Hello => ("Hello".to_string(), 0),
+        Command::SetOrientation => {
+            let orientation = if data[1] == 0 {
+                "portrait"
+            } else {
+                "landscape"
+            };
+            (format!("Orientation {}", orientation), 0)
+        }
+        Command::SetBrightness => (format!("Brightness (raw {})", data[1]), 0),
+        Command::DisplayBitmap => {
+            let (x, y, ex, ey) = (word(1), word(3), word(5), word(7));
+            let payload_len = if ex >= x && ey >= y {
+                (ex - x + 1) as usize * (ey - y + 1) as usize * 2
+            } else {
+                0
+            };
+            (
+                format!(
+                    "DisplayImage ({}, {})-({}, {}) payload {} bytes",
+                    x, y, ex, ey, payload_len
+                ),
+                payload_len,
+            )
+        }
+    };
+
+    Ok(DecodedCommand {
+        description,
+        len: PACKET_SIZE,
+        payload_len,
+    })
+}
+
+/// XuanFang revision B.
+///
+/// The hardware only knows portrait and landscape, so the reverse orientations are done
+/// here by rotating every bitmap 180 degrees.
+pub struct RevB {
+    sub_revision: u16,
+    brightness: u8,
+    reversed: bool,
+    width: u16,
+    height: u16,
+}
+
+impl RevB {
+    pub fn new(sub_revision: u16) -> Self {
+        RevB {
+            sub_revision,
+            brightness: 0,
+            reversed: false,
+            width: 0,
+            height: 0,
+        }
+    }
+
+    pub fn hello_packet() -> Vec<u8> {
+        create_packet(Command::Hello, b"HELLO")
+    }
+
+    /// Recognises the reply to `hello_packet`, which is framed like a command.
+    pub fn from_hello_reply(reply: &[u8]) -> Option<Self> {
+        let hello = Command::Hello.get_id();
+        if reply.len() != HELLO_REPLY_SIZE || reply[0] != hello || reply[9] != hello {
+            return None;
+        }
+
+        Some(RevB::new(u16::from_be_bytes([reply[6], reply[7]])))
+    }
+
+    /// Flagship units have an RGB LED strip and only an on/off backlight.
+    fn is_flagship(&self) -> bool {
+        matches!(self.sub_revision, SUB_REVISION_A01 | SUB_REVISION_A11)
+    }
+
+    fn has_brightness_range(&self) -> bool {
+        matches!(self.sub_revision, SUB_REVISION_A02 | SUB_REVISION_A12)
+    }
+
+    fn brightness_packet(&self, level: u8) -> Vec<u8> {
+        let converted = if self.has_brightness_range() {
+            // 0 is darkest, 255 is brightest
+            ((level as f32 / 100.0) * 255.0).round() as u8
+        } else {
+            // 0 is full brightness, 1 is off
+            if level == 0 { 1 } else { 0 }
+        };
+
+        create_packet(Command::SetBrightness, &[converted])
+    }
+}
+
+impl Protocol for RevB {
+    fn revision(&self) -> Revision {
+        Revision::B
+    }
+
+    fn hello(&self) -> Vec<u8> {
+        RevB::hello_packet()
+    }
+
+    fn ping(&self) -> Option<Vec<u8>> {
+        Some(RevB::hello_packet())
+    }
+
+    fn hello_reply_len(&self) -> usize {
+        HELLO_REPLY_SIZE
+    }
+
+    fn hello_reply(&mut self, reply: &[u8]) -> Result<DeviceInfo, String> {
+        match RevB::from_hello_reply(reply) {
+            Some(detected) => self.sub_revision = detected.sub_revision,
+            None if !reply.is_empty() => {
+                return Err(format!(
+                    "unexpected reply to revision B hello: {:02X?}",
+                    reply
+                ));
+            }
+            None => {}
+        }
+
+        let mut sub_revision = format!("{:X}", self.sub_revision);
+        if self.is_flagship() {
+            sub_revision.push_str(" (flagship, RGB LED strip)");
+        }
+
+        Ok(DeviceInfo {
+            revision: Revision::B,
+            sub_revision,
+            resolution: Some((320, 480)),
+        })
+    }
+
+    fn screen_on(&mut self) -> Vec<u8> {
+        self.brightness_packet(self.brightness)
+    }
+
+    fn screen_off(&mut self) -> Vec<u8> {
+        self.brightness_packet(0)
+    }
+
+    fn brightness(&mut self, level: u8) -> Result<Vec<u8>, String> {
+        if level > 100 {
+            return Err("Brightness level must be between 0 and 100".to_string());
+        }
+
+        self.brightness = level;
+        Ok(self.brightness_packet(level))
+    }
+
+    fn orientation(&mut self, orientation: Orientation, width: u16, height: u16) -> Vec<u8> {
+        let value = match orientation {
+            Orientation::Portrait | Orientation::ReversePortrait => 0,
+            Orientation::Landscape | Orientation::ReverseLandscape => 1,
+        };
+
+        self.reversed = matches!(
+            orientation,
+            Orientation::ReversePortrait | Orientation::ReverseLandscape
+        );
+        self.width = width;
+        self.height = height;
+
+        create_packet(Command::SetOrientation, &[value])
+    }
+
+    fn display_image(
+        &mut self,
+        x: u16,
+        y: u16,
+        width: u16,
+        height: u16,
+    ) -> Result<Vec<u8>, EncodeError> {
+        let (ex, ey) = region_end(x, y, width, height, self.width as u32, self.height as u32)?;
+
+        // The region is on screen, so mirroring it can't wrap
+        let (x, y, ex, ey) = if self.reversed {
+            (
+                self.width - 1 - ex,
+                self.height - 1 - ey,
+                self.width - 1 - x,
+                self.height - 1 - y,
+            )
+        } else {
+            (x, y, ex, ey)
+        };
+
+        let mut payload = [0u8; 8];
+        payload[0..2].copy_from_slice(&x.to_be_bytes());
+        payload[2..4].copy_from_slice(&y.to_be_bytes());
+        payload[4..6].copy_from_slice(&ex.to_be_bytes());
+        payload[6..8].copy_from_slice(&ey.to_be_bytes());
+
+        Ok(create_packet(Command::DisplayBitmap, &payload))
+    }
+
+    fn pixel_format(&self) -> PixelFormat {
+        PixelFormat::Rgb565Be
+    }
+
+    fn encode_pixels<'a>(
+        &self,
+        _x: u16,
+        _y: u16,
+        _width: u16,
+        _height: u16,
+        rgb565: &'a [u8],
+    ) -> Cow<'a, [u8]> {
+        let encoded = PixelFormat::Rgb565Le.convert(rgb565, self.pixel_format());
+        if !self.reversed {
+            return Cow::Owned(encoded);
+        }
+
+        // Reversing the pixel order rotates the bitmap by 180 degrees
+        let reversed = encoded.chunks_exact(2).rev().flatten().copied().collect();
+        Cow::Owned(reversed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bitmap_region(packet: &[u8]) -> (u16, u16, u16, u16) {
+        let word = |index: usize| u16::from_be_bytes([packet[index], packet[index + 1]]);
+        (word(1), word(3), word(5), word(7))
+    }
+
+    fn hello_reply(sub_revision: u16) -> Vec<u8> {
+        let mut reply = vec![0; HELLO_REPLY_SIZE];
+        reply[0] = Command::Hello.get_id();
+        reply[6..8].copy_from_slice(&sub_revision.to_be_bytes());
+        reply[9] = Command::Hello.get_id();
+        reply
+    }
+
+    #[test]
+    fn flagship_models_are_reported() {
+        let mut protocol = RevB::new(0);
+        let info = protocol
+            .hello_reply(&hello_reply(SUB_REVISION_A11))
+            .unwrap();
+        assert_eq!(info.sub_revision, "A11 (flagship, RGB LED strip)");
+
+        let info = protocol
+            .hello_reply(&hello_reply(SUB_REVISION_A12))
+            .unwrap();
+        assert_eq!(info.sub_revision, "A12");
+    }
+
+    #[test]
+    fn brightness_depends_on_the_sub_revision() {
+        // Flagship backlights are either on or off
+        let mut protocol = RevB::new(SUB_REVISION_A01);
+        assert_eq!(protocol.brightness(40).unwrap()[1], 0);
+        assert_eq!(protocol.brightness(0).unwrap()[1], 1);
+
+        let mut protocol = RevB::new(SUB_REVISION_A02);
+        assert_eq!(protocol.brightness(40).unwrap()[1], 102);
+        assert_eq!(protocol.brightness(100).unwrap()[1], 255);
+    }
+
+    #[test]
+    fn regions_are_checked_against_the_screen() {
+        let mut protocol = RevB::new(SUB_REVISION_A01);
+        protocol.orientation(Orientation::Portrait, 320, 480);
+
+        let packet = protocol.display_image(310, 470, 10, 10).unwrap();
+        assert_eq!(bitmap_region(&packet), (310, 470, 319, 479));
+
+        assert!(matches!(
+            protocol.display_image(311, 0, 10, 10),
+            Err(EncodeError::OutOfBounds { .. })
+        ));
+        assert!(matches!(
+            protocol.display_image(u16::MAX, 0, 1, 1),
+            Err(EncodeError::OutOfBounds { .. })
+        ));
+        assert!(matches!(
+            protocol.display_image(0, 0, 0, 10),
+            Err(EncodeError::ZeroSize { .. })
+        ));
+    }
+
+    #[test]
+    fn reversed_regions_are_mirrored() {
+        let mut protocol = RevB::new(SUB_REVISION_A11);
+        protocol.orientation(Orientation::ReverseLandscape, 480, 320);
+
+        let packet = protocol.display_image(0, 0, 10, 20).unwrap();
+        assert_eq!(bitmap_region(&packet), (470, 300, 479, 319));
+
+        assert!(matches!(
+            protocol.display_image(475, 0, 10, 10),
+            Err(EncodeError::OutOfBounds { .. })
+        ));
+    }
+}
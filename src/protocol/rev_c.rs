@@ -0,0 +1,358 @@
+use crate::device::Orientation;
+use crate::packets::{DecodeError, EncodeError, region_end};
+use crate::pixel_format::PixelFormat;
+use crate::protocol::{DecodedCommand, DeviceInfo, Protocol, Revision};
+use std::borrow::Cow;
+
+const HELLO: [u8; 12] = [
+    0x01, 0xEF, 0x69, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0xC5, 0xD3,
+];
+const TURN_ON: [u8; 7] = [0x83, 0xEF, 0x69, 0x00, 0x00, 0x00, 0x00];
+const TURN_OFF: [u8; 7] = [0x83, 0xEF, 0x69, 0x00, 0x00, 0x00, 0x01];
+const SET_BRIGHTNESS: [u8; 10] = [0x7B, 0xEF, 0x69, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00];
+const QUERY_STATUS: [u8; 7] = [0xCF, 0xEF, 0x69, 0x00, 0x00, 0x00, 0x01];
+/// Full screen upload, the last three bytes are the BGRA payload size (800 * 480 * 4).
+const DISPLAY_BITMAP: [u8; 6] = [0xC8, 0xEF, 0x69, 0x00, 0x17, 0x70];
+const UPDATE_BITMAP: [u8; 4] = [0xCC, 0xEF, 0x69, 0x00];
+const START_DISPLAY_BITMAP: u8 = 0x2C;
+const PAYLOAD_END: [u8; 2] = [0xEF, 0x69];
+
+/// Commands are padded out to a whole number of blocks.
+pub const BLOCK_SIZE: usize = 250;
+pub const HELLO_REPLY_SIZE: usize = 23;
+
+/// The panel's framebuffer is landscape, whatever the orientation.
+const NATIVE_WIDTH: u16 = 800;
+const NATIVE_HEIGHT: u16 = 480;
+
+fn pad_to_block(mut message: Vec<u8>, padding: u8) -> Vec<u8> {
+    let padded_len = message.len().div_ceil(BLOCK_SIZE) * BLOCK_SIZE;
+    message.resize(padded_len, padding);
+    message
+}
+
+fn create_packet(command: &[u8], payload: &[u8]) -> Vec<u8> {
+    let mut message = command.to_vec();
+    message.extend_from_slice(payload);
+    pad_to_block(message, 0x00)
+}
+
+/// Length on the wire of a partial update whose row records, end marker included, take
+/// `size` bytes.
+fn update_wire_len(size: usize) -> usize {
+    let records = size.saturating_sub(PAYLOAD_END.len());
+    let separators = records.div_ceil(249).saturating_sub(1);
+    (records + separators + PAYLOAD_END.len()).div_ceil(BLOCK_SIZE) * BLOCK_SIZE
+}
+
+/// Decodes the padded command at the start of `data`.
+pub fn decode_command(data: &[u8]) -> Result<DecodedCommand, DecodeError> {
+    if data.len() < BLOCK_SIZE {
+        return Err(DecodeError::Incomplete(BLOCK_SIZE));
+    }
+    let block = &data[..BLOCK_SIZE];
+
+    let (description, len, payload_len) = if block.iter().all(|&b| b == START_DISPLAY_BITMAP) {
+        if data.len() < BLOCK_SIZE * 2 {
+            return Err(DecodeError::Incomplete(BLOCK_SIZE * 2));
+        }
+        if !data[BLOCK_SIZE..].starts_with(&DISPLAY_BITMAP) {
+            return Err(DecodeError::UnknownCommand(data[BLOCK_SIZE]));
+        }
+        let payload_len = NATIVE_WIDTH as usize * NATIVE_HEIGHT as usize * 4;
+        (
+            format!("DisplayBitmap payload {} bytes", payload_len),
+            BLOCK_SIZE * 2,
+            payload_len,
+        )
+    } else if block.starts_with(&HELLO) {
+        ("Hello".to_string(), BLOCK_SIZE, 0)
+    } else if block.starts_with(&TURN_ON) {
+        ("ScreenOn".to_string(), BLOCK_SIZE, 0)
+    } else if block.starts_with(&TURN_OFF) {
+        ("ScreenOff".to_string(), BLOCK_SIZE, 0)
+    } else if block.starts_with(&SET_BRIGHTNESS) {
+        let level = block[SET_BRIGHTNESS.len()];
+        (format!("Brightness (raw {})", level), BLOCK_SIZE, 0)
+    } else if block.starts_with(&QUERY_STATUS) {
+        ("QueryStatus".to_string(), BLOCK_SIZE, 0)
+    } else if block.starts_with(&UPDATE_BITMAP) {
+        let size = u32::from_be_bytes([0, block[4], block[5], block[6]]) as usize;
+        let count = u32::from_be_bytes([block[10], block[11], block[12], block[13]]);
+        let payload_len = update_wire_len(size);
+        (
+            format!("UpdateBitmap #{} payload {} bytes", count, payload_len),
+            BLOCK_SIZE,
+            payload_len,
+        )
+    } else {
+        return Err(DecodeError::UnknownCommand(block[0]));
+    };
+
+    Ok(DecodedCommand {
+        description,
+        len,
+        payload_len,
+    })
+}
+
+/// A region of the landscape framebuffer.
+#[derive(Clone, Copy)]
+struct NativeRegion {
+    x: u16,
+    y: u16,
+    width: u16,
+    height: u16,
+}
+
+/// Turing Smart Screen 5" revision C.
+///
+/// There is no orientation command, so every bitmap is rotated here into the panel's
+/// landscape framebuffer. Full screen updates go out as one BGRA bitmap, anything
+/// smaller as BGR row records.
+pub struct RevC {
+    orientation: Orientation,
+    update_count: u32,
+}
+
+impl RevC {
+    pub fn new() -> Self {
+        RevC {
+            orientation: Orientation::Landscape,
+            update_count: 0,
+        }
+    }
+
+    /// The hello command on its own, for probing which protocol a panel speaks.
+    pub fn hello_packet() -> Vec<u8> {
+        create_packet(&HELLO, &[])
+    }
+
+    /// Recognises the reply to `hello_packet`, e.g. "chs_5inch.dev1_rom1.87".
+    pub fn is_hello_reply(reply: &[u8]) -> bool {
+        reply.starts_with(b"chs_")
+    }
+
+    fn is_full_screen(&self, region: NativeRegion) -> bool {
+        region.x == 0
+            && region.y == 0
+            && region.width == NATIVE_WIDTH
+            && region.height == NATIVE_HEIGHT
+    }
+
+    /// Maps a region in the current orientation onto the landscape framebuffer.
+    fn native_region(&self, x: u16, y: u16, width: u16, height: u16) -> NativeRegion {
+        match self.orientation {
+            Orientation::Landscape => NativeRegion {
+                x,
+                y,
+                width,
+                height,
+            },
+            Orientation::ReverseLandscape => NativeRegion {
+                x: NATIVE_WIDTH.saturating_sub(x + width),
+                y: NATIVE_HEIGHT.saturating_sub(y + height),
+                width,
+                height,
+            },
+            Orientation::Portrait => NativeRegion {
+                x: y,
+                y: NATIVE_HEIGHT.saturating_sub(x + width),
+                width: height,
+                height: width,
+            },
+            Orientation::ReversePortrait => NativeRegion {
+                x: NATIVE_WIDTH.saturating_sub(y + height),
+                y: x,
+                width: height,
+                height: width,
+            },
+        }
+    }
+
+    /// Returns the source pixel index for column `col` and row `row` of the native region.
+    fn source_index(&self, width: usize, height: usize, col: usize, row: usize) -> usize {
+        let (src_col, src_row) = match self.orientation {
+            Orientation::Landscape => (col, row),
+            Orientation::ReverseLandscape => (width - 1 - col, height - 1 - row),
+            Orientation::Portrait => (width - 1 - row, col),
+            Orientation::ReversePortrait => (row, height - 1 - col),
+        };
+        src_row * width + src_col
+    }
+
+    /// Expands little-endian RGB565 to BGR, reordered into native rows.
+    fn native_bgr(&self, width: u16, height: u16, rgb565: &[u8]) -> Vec<[u8; 3]> {
+        let (width, height) = (width as usize, height as usize);
+        let (native_width, native_height) = match self.orientation {
+            Orientation::Portrait | Orientation::ReversePortrait => (height, width),
+            Orientation::Landscape | Orientation::ReverseLandscape => (width, height),
+        };
+        let mut pixels = Vec::with_capacity(width * height);
+
+        for row in 0..native_height {
+            for col in 0..native_width {
+                let index = self.source_index(width, height, col, row) * 2;
+                let [r, g, b] = match rgb565.get(index..index + 2) {
+                    Some(bytes) => PixelFormat::Rgb565Le.decode(bytes),
+                    None => [0, 0, 0],
+                };
+                pixels.push([b, g, r]);
+            }
+        }
+
+        pixels
+    }
+
+    /// Size of the row records for a partial update, including the end marker.
+    fn update_payload_len(region: NativeRegion) -> usize {
+        region.height as usize * (5 + region.width as usize * 3) + PAYLOAD_END.len()
+    }
+}
+
+impl Protocol for RevC {
+    fn revision(&self) -> Revision {
+        Revision::C
+    }
+
+    fn hello(&self) -> Vec<u8> {
+        Self::hello_packet()
+    }
+
+    /// The panel answers a status query with a short string such as "needReSend:0".
+    fn ping(&self) -> Option<Vec<u8>> {
+        Some(create_packet(&QUERY_STATUS, &[]))
+    }
+
+    fn hello_reply_len(&self) -> usize {
+        HELLO_REPLY_SIZE
+    }
+
+    fn hello_reply(&mut self, reply: &[u8]) -> Result<DeviceInfo, String> {
+        // e.g. "chs_5inch.dev1_rom1.87"
+        let reply = String::from_utf8_lossy(reply);
+        let sub_revision = reply.trim_matches(char::from(0)).trim().to_string();
+        if !sub_revision.is_empty() && !sub_revision.starts_with("chs_") {
+            return Err(format!(
+                "unexpected reply to revision C hello: {:?}",
+                sub_revision
+            ));
+        }
+
+        Ok(DeviceInfo {
+            revision: Revision::C,
+            sub_revision,
+            resolution: Some((NATIVE_HEIGHT, NATIVE_WIDTH)),
+        })
+    }
+
+    fn screen_on(&mut self) -> Vec<u8> {
+        create_packet(&TURN_ON, &[])
+    }
+
+    fn screen_off(&mut self) -> Vec<u8> {
+        create_packet(&TURN_OFF, &[])
+    }
+
+    fn brightness(&mut self, level: u8) -> Result<Vec<u8>, String> {
+        if level > 100 {
+            return Err("Brightness level must be between 0 and 100".to_string());
+        }
+
+        let converted = ((level as f32 / 100.0) * 255.0).round() as u8;
+        Ok(create_packet(&SET_BRIGHTNESS, &[converted]))
+    }
+
+    fn orientation(&mut self, orientation: Orientation, _width: u16, _height: u16) -> Vec<u8> {
+        self.orientation = orientation;
+        Vec::new()
+    }
+
+    fn display_image(
+        &mut self,
+        x: u16,
+        y: u16,
+        width: u16,
+        height: u16,
+    ) -> Result<Vec<u8>, EncodeError> {
+        let (screen_width, screen_height) = match self.orientation {
+            Orientation::Portrait | Orientation::ReversePortrait => (NATIVE_HEIGHT, NATIVE_WIDTH),
+            Orientation::Landscape | Orientation::ReverseLandscape => (NATIVE_WIDTH, NATIVE_HEIGHT),
+        };
+        region_end(
+            x,
+            y,
+            width,
+            height,
+            screen_width as u32,
+            screen_height as u32,
+        )?;
+
+        let region = self.native_region(x, y, width, height);
+
+        if self.is_full_screen(region) {
+            let mut packet = pad_to_block(vec![START_DISPLAY_BITMAP], START_DISPLAY_BITMAP);
+            packet.extend(create_packet(&DISPLAY_BITMAP, &[]));
+            return Ok(packet);
+        }
+
+        let size = RevC::update_payload_len(region) as u32;
+        let mut payload = Vec::with_capacity(10);
+        payload.extend_from_slice(&size.to_be_bytes()[1..]);
+        payload.extend_from_slice(&[0x00; 3]);
+        payload.extend_from_slice(&self.update_count.to_be_bytes());
+        self.update_count = self.update_count.wrapping_add(1);
+
+        Ok(create_packet(&UPDATE_BITMAP, &payload))
+    }
+
+    /// Partial updates are BGR, full screen ones add a padding byte to make BGRA.
+    fn pixel_format(&self) -> PixelFormat {
+        PixelFormat::Bgr888
+    }
+
+    fn encode_pixels<'a>(
+        &self,
+        x: u16,
+        y: u16,
+        width: u16,
+        height: u16,
+        rgb565: &'a [u8],
+    ) -> Cow<'a, [u8]> {
+        let region = self.native_region(x, y, width, height);
+        let pixels = self.native_bgr(width, height, rgb565);
+
+        if self.is_full_screen(region) {
+            let encoded = pixels
+                .iter()
+                .flat_map(|[b, g, r]| [*b, *g, *r, 0xFF])
+                .collect();
+            return Cow::Owned(encoded);
+        }
+
+        // One record per row: framebuffer offset, row width, then the pixels
+        let mut records = Vec::with_capacity(RevC::update_payload_len(region));
+        for (row, row_pixels) in pixels.chunks(region.width as usize).enumerate() {
+            let offset = (region.y as u32 + row as u32) * NATIVE_WIDTH as u32 + region.x as u32;
+            records.extend_from_slice(&offset.to_be_bytes()[1..]);
+            records.extend_from_slice(&region.width.to_be_bytes());
+            records.extend(row_pixels.iter().flatten());
+        }
+
+        // The panel expects a zero byte after every 249 bytes of record data
+        let mut encoded = Vec::with_capacity(records.len() + records.len() / 249 + 2);
+        for (index, block) in records.chunks(249).enumerate() {
+            if index > 0 {
+                encoded.push(0x00);
+            }
+            encoded.extend_from_slice(block);
+        }
+        encoded.extend_from_slice(&PAYLOAD_END);
+
+        Cow::Owned(pad_to_block(encoded, 0x00))
+    }
+
+    fn display_image_end(&mut self) -> Vec<u8> {
+        create_packet(&QUERY_STATUS, &[])
+    }
+}
@@ -0,0 +1,309 @@
+use crate::packets::{DecodeError, PACKET_SIZE, Packet};
+use crate::pixel_format::PixelFormat;
+use crate::transport::Transport;
+use image::{Rgb, RgbImage};
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// A bitmap upload that is still waiting for pixel data.
+struct Upload {
+    x: u16,
+    y: u16,
+    width: u16,
+    height: u16,
+    received: usize,
+}
+
+impl Upload {
+    fn total_pixels(&self) -> usize {
+        self.width as usize * self.height as usize
+    }
+}
+
+/// A software model of the 3.5" revision A panel.
+///
+/// It consumes the same byte stream `Device` writes to the serial port and keeps the
+/// resulting picture in a framebuffer. Like the real panel, pixels are stored in native
+/// (portrait) order and the orientation only changes how incoming coordinates are mapped.
+pub struct Simulator {
+    width: u16,
+    height: u16,
+    orientation: u8,
+    framebuffer: Vec<[u8; 3]>,
+    brightness: u8,
+    screen_on: bool,
+    pending: Vec<u8>,
+    upload: Option<Upload>,
+    unknown_commands: usize,
+}
+
+impl Simulator {
+    /// Creates a simulator for a panel with the given native (portrait) resolution.
+    pub fn new(width: u16, height: u16) -> Self {
+        Simulator {
+            width,
+            height,
+            orientation: 0,
+            framebuffer: vec![[0, 0, 0]; width as usize * height as usize],
+            brightness: 0,
+            screen_on: true,
+            pending: Vec::new(),
+            upload: None,
+            unknown_commands: 0,
+        }
+    }
+
+    /// Feeds bytes exactly as they would arrive over the serial port. Commands may be
+    /// split across calls at any byte boundary.
+    pub fn feed(&mut self, data: &[u8]) {
+        self.pending.extend_from_slice(data);
+
+        let mut consumed = 0;
+        loop {
+            let available = &self.pending[consumed..];
+
+            if let Some(upload) = &mut self.upload {
+                let remaining = (upload.total_pixels() - upload.received) * 2;
+                let usable = remaining.min(available.len() & !1);
+                if usable == 0 {
+                    break;
+                }
+
+                let (x, y, width) = (upload.x, upload.y, upload.width as usize);
+                let first = upload.received;
+                upload.received += usable / 2;
+                let finished = upload.received == upload.total_pixels();
+
+                let pixels: Vec<[u8; 3]> = available[..usable]
+                    .chunks_exact(2)
+                    .map(|bytes| PixelFormat::Rgb565Le.decode(bytes))
+                    .collect();
+                for (i, rgb) in pixels.into_iter().enumerate() {
+                    let index = first + i;
+                    let px = x as usize + index % width;
+                    let py = y as usize + index / width;
+                    self.set_pixel(px, py, rgb);
+                }
+
+                if finished {
+                    self.upload = None;
+                }
+                consumed += usable;
+                continue;
+            }
+
+            match Packet::decode(available) {
+                Ok((packet, size)) => {
+                    consumed += size;
+                    self.apply(packet);
+                }
+                Err(DecodeError::Incomplete(_)) => break,
+                Err(_) => {
+                    consumed += PACKET_SIZE;
+                    self.unknown_commands += 1;
+                }
+            }
+        }
+
+        self.pending.drain(..consumed);
+    }
+
+    fn apply(&mut self, packet: Packet) {
+        match packet {
+            Packet::Reset => {
+                // The panel comes back up blank and in its default state
+                self.orientation = 0;
+                self.framebuffer.fill([0, 0, 0]);
+                self.screen_on = true;
+            }
+            Packet::Hello => {}
+            Packet::ScreenWhite => self.framebuffer.fill([255, 255, 255]),
+            Packet::ScreenBlack => self.framebuffer.fill([0, 0, 0]),
+            Packet::ScreenOff => self.screen_on = false,
+            Packet::ScreenOn => self.screen_on = true,
+            Packet::Brightness { level_absolute } => {
+                self.brightness = Packet::brightness_percent(level_absolute);
+            }
+            Packet::Orientation { orientation, .. } => self.orientation = orientation.get_id(),
+            Packet::DisplayImage { x, y, ex, ey } => {
+                if ex >= x && ey >= y {
+                    self.upload = Some(Upload {
+                        x,
+                        y,
+                        width: ex - x + 1,
+                        height: ey - y + 1,
+                        received: 0,
+                    });
+                }
+            }
+        }
+    }
+
+    fn logical_size(&self) -> (u16, u16) {
+        match self.orientation {
+            2 | 3 => (self.height, self.width),
+            _ => (self.width, self.height),
+        }
+    }
+
+    /// Maps a coordinate in the current orientation onto native framebuffer order.
+    fn native_index(&self, x: usize, y: usize) -> Option<usize> {
+        let (logical_width, logical_height) = self.logical_size();
+        if x >= logical_width as usize || y >= logical_height as usize {
+            return None;
+        }
+
+        let (w, h) = (self.width as usize, self.height as usize);
+        let (nx, ny) = match self.orientation {
+            1 => (w - 1 - x, h - 1 - y),
+            2 => (y, h - 1 - x),
+            3 => (w - 1 - y, x),
+            _ => (x, y),
+        };
+
+        Some(ny * w + nx)
+    }
+
+    fn set_pixel(&mut self, x: usize, y: usize, rgb: [u8; 3]) {
+        if let Some(index) = self.native_index(x, y) {
+            self.framebuffer[index] = rgb;
+        }
+    }
+
+    /// Renders the framebuffer as it appears in the current orientation.
+    pub fn snapshot(&self) -> RgbImage {
+        let (width, height) = self.logical_size();
+        RgbImage::from_fn(width as u32, height as u32, |x, y| {
+            match self.native_index(x as usize, y as usize) {
+                Some(index) if self.screen_on => Rgb(self.framebuffer[index]),
+                _ => Rgb([0, 0, 0]),
+            }
+        })
+    }
+
+    pub fn save_png<P: AsRef<Path>>(&self, path: P) -> image::ImageResult<()> {
+        self.snapshot().save(path)
+    }
+
+    pub fn brightness(&self) -> u8 {
+        self.brightness
+    }
+
+    pub fn is_screen_on(&self) -> bool {
+        self.screen_on
+    }
+
+    /// Number of 6-byte commands that did not match any known packet id.
+    pub fn unknown_commands(&self) -> usize {
+        self.unknown_commands
+    }
+}
+
+/// Lets a `Device` drive a shared `Simulator` in place of a serial port.
+pub struct SimulatorTransport {
+    simulator: Arc<Mutex<Simulator>>,
+}
+
+impl SimulatorTransport {
+    pub fn new(simulator: Arc<Mutex<Simulator>>) -> Self {
+        SimulatorTransport { simulator }
+    }
+}
+
+impl Transport for SimulatorTransport {
+    fn write_all(&mut self, data: &[u8]) -> io::Result<()> {
+        self.simulator.lock().unwrap().feed(data);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::{Device, Orientation};
+    use crate::protocol::rev_a::RevA;
+
+    fn open(simulator: &Arc<Mutex<Simulator>>) -> Device {
+        let transport = SimulatorTransport::new(simulator.clone());
+        Device::with_protocol(Box::new(transport), Box::new(RevA::new()), 2, 4).unwrap()
+    }
+
+    #[test]
+    fn device_commands_show_up_on_the_simulated_panel() {
+        let simulator = Arc::new(Mutex::new(Simulator::new(2, 4)));
+        let mut device = open(&simulator);
+
+        device.set_brightness(40).unwrap();
+        device.set_orientation(Orientation::Landscape).unwrap();
+        // Red in the top left corner, blue along the bottom right
+        let red = [0x00, 0xF8];
+        let blue = [0x1F, 0x00];
+        device.display_picture(red.to_vec(), 0, 0, 1, 1).unwrap();
+        device.display_picture(blue.repeat(3), 1, 1, 3, 1).unwrap();
+
+        let simulator_ref = simulator.lock().unwrap();
+        assert_eq!(simulator_ref.brightness(), 40);
+        assert!(simulator_ref.is_screen_on());
+        assert_eq!(simulator_ref.unknown_commands(), 0);
+
+        let snapshot = simulator_ref.snapshot();
+        assert_eq!(snapshot.dimensions(), (4, 2));
+        assert_eq!(snapshot.get_pixel(0, 0), &Rgb([255, 0, 0]));
+        assert_eq!(snapshot.get_pixel(1, 0), &Rgb([0, 0, 0]));
+        assert_eq!(snapshot.get_pixel(0, 1), &Rgb([0, 0, 0]));
+        for x in 1..4 {
+            assert_eq!(snapshot.get_pixel(x, 1), &Rgb([0, 0, 255]));
+        }
+        drop(simulator_ref);
+
+        // An unlit panel shows nothing, but keeps its picture for when it comes back
+        device.screen_off().unwrap();
+        assert!(!simulator.lock().unwrap().is_screen_on());
+        let snapshot = simulator.lock().unwrap().snapshot();
+        assert!(snapshot.pixels().all(|pixel| pixel == &Rgb([0, 0, 0])));
+
+        device.screen_on().unwrap();
+        let snapshot = simulator.lock().unwrap().snapshot();
+        assert_eq!(snapshot.get_pixel(0, 0), &Rgb([255, 0, 0]));
+    }
+
+    #[test]
+    fn commands_split_across_writes_are_reassembled() {
+        let simulator = Arc::new(Mutex::new(Simulator::new(2, 4)));
+        let mut transport = SimulatorTransport::new(simulator.clone());
+
+        let mut stream = Packet::DisplayImage {
+            x: 0,
+            y: 0,
+            ex: 1,
+            ey: 0,
+        }
+        .encode()
+        .unwrap();
+        stream.extend([0xFF; 4]);
+        for byte in stream {
+            transport.write_all(&[byte]).unwrap();
+        }
+
+        let snapshot = simulator.lock().unwrap().snapshot();
+        assert_eq!(snapshot.get_pixel(0, 0), &Rgb([255, 255, 255]));
+        assert_eq!(snapshot.get_pixel(1, 0), &Rgb([255, 255, 255]));
+        assert_eq!(snapshot.get_pixel(0, 1), &Rgb([0, 0, 0]));
+    }
+
+    #[test]
+    fn unknown_commands_are_counted_and_skipped() {
+        let simulator = Arc::new(Mutex::new(Simulator::new(2, 4)));
+        let mut device = open(&simulator);
+
+        SimulatorTransport::new(simulator.clone())
+            .write_all(&[0, 0, 0, 0, 0, 0x12])
+            .unwrap();
+        device.set_brightness(75).unwrap();
+
+        let simulator = simulator.lock().unwrap();
+        assert_eq!(simulator.unknown_commands(), 1);
+        assert_eq!(simulator.brightness(), 75);
+    }
+}
@@ -0,0 +1,444 @@
+use crate::packets::DecodeError;
+use crate::protocol::{self, Revision};
+use crate::transport::Transport;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Every trace file starts with this, followed by the format version and, from version 2,
+/// the protocol revision the panel spoke. Version 1 traces were all revision A.
+const MAGIC: &[u8; 8] = b"GIBTRACE";
+const VERSION: u8 = 2;
+
+/// The longest pause a realtime replay keeps. Traces can span idle periods and
+/// reconnects, which are only worth waiting out up to a point.
+const MAX_REPLAY_GAP: Duration = Duration::from_secs(1);
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+fn write_header<W: Write>(out: &mut W, revision: Revision) -> io::Result<()> {
+    out.write_all(MAGIC)?;
+    out.write_all(&[VERSION, revision.get_id()])
+}
+
+/// Reads the header and returns the revision the trace was recorded from.
+fn read_header<R: Read>(input: &mut R) -> io::Result<Revision> {
+    let mut header = [0u8; 9];
+    input.read_exact(&mut header)?;
+    if &header[..8] != MAGIC {
+        return Err(invalid_data("not a gibmon trace file"));
+    }
+
+    match header[8] {
+        1 => Ok(Revision::A),
+        VERSION => {
+            let mut id = [0u8; 1];
+            input.read_exact(&mut id)?;
+            Revision::from_id(id[0]).ok_or_else(|| invalid_data("unknown protocol revision"))
+        }
+        _ => Err(invalid_data("unsupported trace version")),
+    }
+}
+
+/// Which way the bytes in a record went.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Write,
+    Read,
+}
+
+impl Direction {
+    pub fn get_id(&self) -> u8 {
+        match self {
+            Direction::Write => 0,
+            Direction::Read => 1,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(Direction::Write),
+            1 => Some(Direction::Read),
+            _ => None,
+        }
+    }
+}
+
+/// One call to the transport, timestamped from the Unix epoch so traces appended after a
+/// reconnect stay in order.
+#[derive(Debug, Clone)]
+pub struct TraceRecord {
+    pub timestamp: Duration,
+    pub direction: Direction,
+    pub data: Vec<u8>,
+}
+
+impl TraceRecord {
+    fn write_to<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        out.write_all(&(self.timestamp.as_micros() as u64).to_le_bytes())?;
+        out.write_all(&[self.direction.get_id()])?;
+        out.write_all(&(self.data.len() as u32).to_le_bytes())?;
+        out.write_all(&self.data)
+    }
+
+    /// Reads the next record, or `None` at the end of the trace.
+    fn read_from<R: Read>(input: &mut R) -> io::Result<Option<Self>> {
+        let mut timestamp = [0u8; 8];
+        match input.read_exact(&mut timestamp) {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(err) => return Err(err),
+        }
+
+        let mut header = [0u8; 5];
+        input.read_exact(&mut header)?;
+        let direction = Direction::from_id(header[0])
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "unknown trace direction"))?;
+        let len = u32::from_le_bytes([header[1], header[2], header[3], header[4]]);
+
+        let mut data = vec![0u8; len as usize];
+        input.read_exact(&mut data)?;
+
+        Ok(Some(TraceRecord {
+            timestamp: Duration::from_micros(u64::from_le_bytes(timestamp)),
+            direction,
+            data,
+        }))
+    }
+}
+
+/// Wraps a transport and logs every byte written to or read from it into a trace file.
+pub struct RecordingTransport {
+    inner: Box<dyn Transport>,
+    trace: BufWriter<File>,
+}
+
+impl RecordingTransport {
+    /// Appends to the trace at `path`, creating it if needed. An existing trace must have
+    /// been recorded from a panel of the same `revision`.
+    pub fn open<P: AsRef<Path>>(
+        inner: Box<dyn Transport>,
+        path: P,
+        revision: Revision,
+    ) -> io::Result<Self> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(path)?;
+        let is_new = file.metadata()?.len() == 0;
+
+        if !is_new && read_header(&mut file)? != revision {
+            return Err(invalid_data(
+                "trace was recorded from a different protocol revision",
+            ));
+        }
+
+        let mut trace = BufWriter::new(file);
+        if is_new {
+            write_header(&mut trace, revision)?;
+        }
+
+        Ok(RecordingTransport { inner, trace })
+    }
+
+    fn record(&mut self, direction: Direction, data: &[u8]) -> io::Result<()> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+
+        TraceRecord {
+            timestamp,
+            direction,
+            data: data.to_vec(),
+        }
+        .write_to(&mut self.trace)
+    }
+}
+
+impl Transport for RecordingTransport {
+    fn write_all(&mut self, data: &[u8]) -> io::Result<()> {
+        // Record first, so a write that fails part way is still in the trace
+        self.record(Direction::Write, data)?;
+        self.inner.write_all(data)
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let count = self.inner.read(buf)?;
+        if count > 0 {
+            self.record(Direction::Read, &buf[..count])?;
+        }
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.trace.flush()?;
+        self.inner.flush()
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        self.inner.set_timeout(timeout)
+    }
+
+    fn discard_input(&mut self) -> io::Result<()> {
+        self.inner.discard_input()
+    }
+}
+
+impl Drop for RecordingTransport {
+    fn drop(&mut self) {
+        let _ = self.trace.flush();
+    }
+}
+
+/// A recorded session: the protocol revision the panel spoke and every transport call.
+#[derive(Debug, Clone)]
+pub struct Trace {
+    pub revision: Revision,
+    pub records: Vec<TraceRecord>,
+}
+
+/// Loads every record in a trace file.
+pub fn read_trace<P: AsRef<Path>>(path: P) -> io::Result<Trace> {
+    let mut input = BufReader::new(File::open(path)?);
+    let revision = read_header(&mut input)?;
+
+    let mut records = Vec::new();
+    while let Some(record) = TraceRecord::read_from(&mut input)? {
+        records.push(record);
+    }
+    Ok(Trace { revision, records })
+}
+
+/// Sends every recorded write to `transport`. With `realtime` the original gaps between
+/// writes are kept, up to `MAX_REPLAY_GAP`, otherwise everything goes out as fast as the
+/// transport takes it.
+pub fn replay(
+    records: &[TraceRecord],
+    transport: &mut dyn Transport,
+    realtime: bool,
+) -> io::Result<()> {
+    let mut previous: Option<Duration> = None;
+
+    for record in records {
+        if record.direction != Direction::Write {
+            continue;
+        }
+
+        if realtime && let Some(previous) = previous {
+            thread::sleep(
+                record
+                    .timestamp
+                    .saturating_sub(previous)
+                    .min(MAX_REPLAY_GAP),
+            );
+        }
+        previous = Some(record.timestamp);
+
+        transport.write_all(&record.data)?;
+    }
+
+    transport.flush()
+}
+
+/// Prints a trace one command per line, with times relative to the first record.
+///
+/// Writes are decoded with the protocol the trace was recorded from, with the pixel
+/// payload after each bitmap command summarised rather than printed. Reads are shown as
+/// hex.
+pub fn dump<W: Write>(trace: &Trace, out: &mut W) -> io::Result<()> {
+    let records = &trace.records;
+    writeln!(out, "revision {:?} trace", trace.revision)?;
+    let Some(start) = records.first().map(|record| record.timestamp) else {
+        return Ok(());
+    };
+
+    let mut pending: Vec<u8> = Vec::new();
+    let mut payload_left = 0;
+
+    for record in records {
+        let millis = record.timestamp.saturating_sub(start).as_secs_f64() * 1000.0;
+
+        if record.direction == Direction::Read {
+            let hex: Vec<String> = record.data.iter().map(|b| format!("{:02X}", b)).collect();
+            writeln!(out, "{:>12.3} ms  <- {}", millis, hex.join(" "))?;
+            continue;
+        }
+
+        pending.extend_from_slice(&record.data);
+
+        loop {
+            if payload_left > 0 {
+                let count = payload_left.min(pending.len());
+                pending.drain(..count);
+                payload_left -= count;
+                if payload_left > 0 {
+                    break;
+                }
+            }
+
+            match protocol::decode_command(trace.revision, &pending) {
+                Ok(command) => {
+                    pending.drain(..command.len);
+                    // Bitmap commands already describe their payload, which is skipped
+                    payload_left = command.payload_len;
+                    writeln!(out, "{:>12.3} ms  -> {}", millis, command.description)?;
+                }
+                Err(DecodeError::Incomplete(_)) => break,
+                Err(err) => {
+                    // Skip the bad command and try to pick the stream back up after it
+                    writeln!(out, "{:>12.3} ms  -> ?? {}", millis, err)?;
+                    let skip = protocol::command_len(trace.revision);
+                    pending.drain(..skip.min(pending.len()));
+                }
+            }
+        }
+    }
+
+    if !pending.is_empty() || payload_left > 0 {
+        writeln!(
+            out,
+            "trace ends mid-command ({} bytes left over, {} bytes of pixels missing)",
+            pending.len(),
+            payload_left
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::Orientation;
+    use crate::protocol::Protocol;
+    use crate::protocol::rev_b::RevB;
+    use crate::protocol::rev_c::RevC;
+    use crate::transport::MemoryTransport;
+    use std::path::PathBuf;
+
+    fn trace_path(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("gibmon-trace-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    fn writes(revision: Revision, writes: &[Vec<u8>]) -> Trace {
+        let records = writes
+            .iter()
+            .enumerate()
+            .map(|(i, data)| TraceRecord {
+                timestamp: Duration::from_millis(i as u64),
+                direction: Direction::Write,
+                data: data.clone(),
+            })
+            .collect();
+        Trace { revision, records }
+    }
+
+    fn dump_lines(trace: &Trace) -> Vec<String> {
+        let mut out = Vec::new();
+        dump(trace, &mut out).unwrap();
+        String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(str::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn long_gaps_are_cut_short_in_realtime_replays() {
+        let mut trace = writes(Revision::A, &[vec![1], vec![2], vec![3]]);
+        trace.records[1].timestamp = Duration::from_secs(600);
+        trace.records[2].timestamp = Duration::from_secs(600) + Duration::from_millis(20);
+
+        let memory = MemoryTransport::new();
+        let started = std::time::Instant::now();
+        replay(&trace.records, &mut memory.clone(), true).unwrap();
+        let elapsed = started.elapsed();
+
+        assert_eq!(memory.written(), vec![1, 2, 3]);
+        assert!(elapsed >= MAX_REPLAY_GAP + Duration::from_millis(20));
+        assert!(elapsed < MAX_REPLAY_GAP * 5, "took {:?}", elapsed);
+    }
+
+    #[test]
+    fn revision_is_kept_in_the_header() {
+        let path = trace_path("header");
+        let mut transport =
+            RecordingTransport::open(Box::new(MemoryTransport::new()), &path, Revision::C).unwrap();
+        transport.write_all(&[1, 2, 3]).unwrap();
+        drop(transport);
+
+        let trace = read_trace(&path).unwrap();
+        assert_eq!(trace.revision, Revision::C);
+        assert_eq!(trace.records.len(), 1);
+        assert_eq!(trace.records[0].data, vec![1, 2, 3]);
+
+        // Appending from a panel speaking another protocol would make it undecodable
+        let err = RecordingTransport::open(Box::new(MemoryTransport::new()), &path, Revision::B)
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn version_1_traces_are_revision_a() {
+        let mut file = MAGIC.to_vec();
+        file.push(1);
+        assert_eq!(read_header(&mut file.as_slice()).unwrap(), Revision::A);
+    }
+
+    #[test]
+    fn revision_b_traces_are_decoded_as_revision_b() {
+        let mut protocol = RevB::new(0x0A01);
+        protocol.orientation(Orientation::Portrait, 320, 480);
+        let bitmap = protocol.display_image(0, 0, 2, 3).unwrap();
+        let trace = writes(
+            Revision::B,
+            &[
+                protocol.hello(),
+                [bitmap, vec![0; 2 * 3 * 2]].concat(),
+                protocol.brightness(50).unwrap(),
+            ],
+        );
+
+        let lines = dump_lines(&trace);
+        assert_eq!(lines.len(), 4, "{:?}", lines);
+        assert!(lines[1].ends_with("-> Hello"));
+        assert!(lines[2].ends_with("-> DisplayImage (0, 0)-(1, 2) payload 12 bytes"));
+        assert!(lines[3].contains("-> Brightness"));
+    }
+
+    #[test]
+    fn revision_c_traces_are_decoded_as_revision_c() {
+        let mut protocol = RevC::new();
+        let update = protocol.display_image(0, 0, 100, 3).unwrap();
+        let pixels = protocol.encode_pixels(0, 0, 100, 3, &[0; 100 * 3 * 2]);
+        let full = protocol.display_image(0, 0, 800, 480).unwrap();
+        let black = vec![0; 480 * 800 * 2];
+        let frame = protocol.encode_pixels(0, 0, 800, 480, &black);
+        let trace = writes(
+            Revision::C,
+            &[
+                protocol.hello(),
+                [update, pixels.into_owned(), protocol.display_image_end()].concat(),
+                [full, frame.into_owned(), protocol.display_image_end()].concat(),
+            ],
+        );
+
+        let lines = dump_lines(&trace);
+        assert_eq!(lines.len(), 6, "{:?}", lines);
+        assert!(lines[1].ends_with("-> Hello"));
+        assert!(lines[2].contains("-> UpdateBitmap #0"));
+        assert!(lines[3].ends_with("-> QueryStatus"));
+        assert!(lines[4].contains("-> DisplayBitmap"));
+        assert!(lines[5].ends_with("-> QueryStatus"));
+    }
+}
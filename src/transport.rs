@@ -0,0 +1,237 @@
+use serialport::{ClearBuffer, SerialPort};
+#[cfg(test)]
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::Path;
+#[cfg(test)]
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// A byte channel that carries the display protocol to wherever it is going.
+pub trait Transport: Send {
+    fn write_all(&mut self, data: &[u8]) -> io::Result<()>;
+
+    /// Reads any reply bytes sent back by the other end. Write-only transports return `Ok(0)`.
+    fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+        Ok(0)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Sets how long reads and writes may block. Transports that never block ignore it.
+    fn set_timeout(&mut self, _timeout: Duration) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Throws away any reply bytes that have arrived but not been read.
+    fn discard_input(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The real hardware: a USB serial port.
+pub struct SerialTransport {
+    port: Box<dyn SerialPort>,
+}
+
+impl SerialTransport {
+    pub fn open(port_name: &str, baud_rate: u32, timeout: Duration) -> serialport::Result<Self> {
+        let port = serialport::new(port_name, baud_rate)
+            .timeout(timeout)
+            .open()?;
+
+        Ok(SerialTransport { port })
+    }
+}
+
+impl Transport for SerialTransport {
+    fn write_all(&mut self, data: &[u8]) -> io::Result<()> {
+        self.port.write_all(data)
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.port.read(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.port.flush()
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        self.port.set_timeout(timeout).map_err(io::Error::from)
+    }
+
+    fn discard_input(&mut self) -> io::Result<()> {
+        self.port.clear(ClearBuffer::Input).map_err(io::Error::from)
+    }
+}
+
+/// Captures everything written into memory and serves canned replies.
+///
+/// Clones share the same buffers, so one copy can be handed to a `Device` while
+/// another is kept to inspect the packet stream.
+#[cfg(test)]
+#[derive(Clone, Default)]
+pub struct MemoryTransport {
+    written: Arc<Mutex<Vec<u8>>>,
+    replies: Arc<Mutex<VecDeque<u8>>>,
+}
+
+#[cfg(test)]
+impl MemoryTransport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns a copy of every byte written so far.
+    pub fn written(&self) -> Vec<u8> {
+        self.written.lock().unwrap().clone()
+    }
+
+    /// Returns and clears every byte written so far.
+    pub fn take_written(&self) -> Vec<u8> {
+        std::mem::take(&mut *self.written.lock().unwrap())
+    }
+
+    /// Queues bytes to be returned by subsequent reads.
+    pub fn push_reply(&self, data: &[u8]) {
+        self.replies.lock().unwrap().extend(data);
+    }
+}
+
+#[cfg(test)]
+impl Transport for MemoryTransport {
+    fn write_all(&mut self, data: &[u8]) -> io::Result<()> {
+        self.written.lock().unwrap().extend_from_slice(data);
+        Ok(())
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut replies = self.replies.lock().unwrap();
+        let count = buf.len().min(replies.len());
+        for (dst, src) in buf.iter_mut().zip(replies.drain(..count)) {
+            *dst = src;
+        }
+        Ok(count)
+    }
+}
+
+/// Streams the protocol over a TCP connection, e.g. to a remote bridge or simulator.
+pub struct TcpTransport {
+    stream: TcpStream,
+}
+
+impl TcpTransport {
+    /// Connects to the first address `addr` resolves to that answers within `timeout`.
+    pub fn connect<A: ToSocketAddrs>(addr: A, timeout: Duration) -> io::Result<Self> {
+        let mut last_err = io::Error::new(io::ErrorKind::NotFound, "address resolved to nothing");
+        for addr in addr.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&addr, timeout) {
+                Ok(stream) => {
+                    stream.set_nodelay(true)?;
+                    stream.set_read_timeout(Some(timeout))?;
+                    stream.set_write_timeout(Some(timeout))?;
+                    return Ok(TcpTransport { stream });
+                }
+                Err(err) => last_err = err,
+            }
+        }
+
+        Err(last_err)
+    }
+}
+
+impl Transport for TcpTransport {
+    fn write_all(&mut self, data: &[u8]) -> io::Result<()> {
+        self.stream.write_all(data)
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream.read(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        self.stream.set_read_timeout(Some(timeout))?;
+        self.stream.set_write_timeout(Some(timeout))
+    }
+}
+
+/// Writes the raw packet stream to a file.
+pub struct FileTransport {
+    file: BufWriter<File>,
+}
+
+impl FileTransport {
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Ok(FileTransport {
+            file: BufWriter::new(File::create(path)?),
+        })
+    }
+}
+
+impl Transport for FileTransport {
+    fn write_all(&mut self, data: &[u8]) -> io::Result<()> {
+        self.file.write_all(data)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    #[test]
+    fn memory_transport_shares_buffers_between_clones() {
+        let memory = MemoryTransport::new();
+        let mut transport = memory.clone();
+
+        transport.write_all(&[1, 2, 3]).unwrap();
+        memory.push_reply(&[9, 8]);
+
+        let mut buf = [0; 4];
+        assert_eq!(transport.read(&mut buf).unwrap(), 2);
+        assert_eq!(&buf[..2], &[9, 8]);
+        assert_eq!(memory.written(), vec![1, 2, 3]);
+        assert_eq!(memory.take_written(), vec![1, 2, 3]);
+        assert!(memory.written().is_empty());
+    }
+
+    #[test]
+    fn tcp_transport_round_trips() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut transport = TcpTransport::connect(addr, Duration::from_secs(1)).unwrap();
+        let (mut peer, _) = listener.accept().unwrap();
+
+        transport.write_all(&[1, 2, 3]).unwrap();
+        let mut buf = [0; 3];
+        peer.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, [1, 2, 3]);
+
+        peer.write_all(&[4]).unwrap();
+        let mut buf = [0; 1];
+        assert_eq!(transport.read(&mut buf).unwrap(), 1);
+        assert_eq!(buf, [4]);
+    }
+
+    #[test]
+    fn tcp_transport_reports_refused_connections() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+
+        assert!(TcpTransport::connect(addr, Duration::from_secs(1)).is_err());
+    }
+}
@@ -0,0 +1,174 @@
+// Only plain byte slices in here, so benches/compositing.rs can build it on its own
+
+/// A layer's pixels and where they sit, as x, y, width and height.
+pub struct Source<'a> {
+    pub bounds: (u32, u32, u32, u32),
+    pub data: &'a [u8],
+}
+
+/// Blends straight-alpha RGBA pixels over an opaque RGBA row of the same length. Runs
+/// of fully transparent pixels are skipped and runs of fully opaque pixels are copied.
+pub fn blend_row(dst: &mut [u8], src: &[u8]) {
+    let len = src.len().min(dst.len()) & !3;
+    let mut i = 0;
+
+    while i < len {
+        match src[i + 3] {
+            0 => {
+                while i < len && src[i + 3] == 0 {
+                    i += 4;
+                }
+            }
+            255 => {
+                let start = i;
+                while i < len && src[i + 3] == 255 {
+                    i += 4;
+                }
+                dst[start..i].copy_from_slice(&src[start..i]);
+            }
+            alpha => {
+                let alpha = alpha as u32;
+                for channel in 0..3 {
+                    dst[i + channel] = mix(src[i + channel], dst[i + channel], alpha);
+                }
+                dst[i + 3] = 255;
+                i += 4;
+            }
+        }
+    }
+}
+
+/// `src * alpha + dst * (255 - alpha)`, divided by 255 and rounded.
+fn mix(src: u8, dst: u8, alpha: u32) -> u8 {
+    let value = src as u32 * alpha + dst as u32 * (255 - alpha) + 128;
+    ((value + (value >> 8)) >> 8) as u8
+}
+
+/// Clears `rect` of a `width` pixel wide RGBA buffer and blends `sources` over it in
+/// order, splitting the rows between up to `threads` threads.
+pub fn composite(
+    buffer: &mut [u8],
+    width: u32,
+    rect: (u32, u32, u32, u32),
+    sources: &[Source],
+    threads: usize,
+) {
+    let (x, y, w, h) = rect;
+    let stride = width as usize * 4;
+    let rows = &mut buffer[y as usize * stride..(y + h) as usize * stride];
+
+    for_each_band(rows, stride, threads, |first, band| {
+        for (offset, row) in band.chunks_exact_mut(stride).enumerate() {
+            let row_y = y + (first + offset) as u32;
+            let row = &mut row[x as usize * 4..(x + w) as usize * 4];
+            row.fill(0);
+
+            for source in sources {
+                let (sx, sy, sw, sh) = source.bounds;
+                if row_y < sy || row_y >= sy + sh {
+                    continue;
+                }
+
+                // Clip the layer's row to the region
+                let start = x.max(sx);
+                let end = (x + w).min(sx + sw);
+                if end <= start {
+                    continue;
+                }
+
+                let src_start = (((row_y - sy) * sw + start - sx) * 4) as usize;
+                let src = &source.data[src_start..src_start + ((end - start) * 4) as usize];
+                blend_row(
+                    &mut row[((start - x) * 4) as usize..((end - x) * 4) as usize],
+                    src,
+                );
+            }
+        }
+    });
+}
+
+/// Splits `data` into bands of whole `row_bytes` rows and runs `f` on each, in parallel
+/// when `threads` is more than one. `f` gets the index of the band's first row.
+pub fn for_each_band<F>(data: &mut [u8], row_bytes: usize, threads: usize, f: F)
+where
+    F: Fn(usize, &mut [u8]) + Sync,
+{
+    let rows = data.len() / row_bytes.max(1);
+    if threads <= 1 || rows <= 1 {
+        f(0, data);
+        return;
+    }
+
+    let band_rows = rows.div_ceil(threads);
+    std::thread::scope(|scope| {
+        for (index, band) in data.chunks_mut(band_rows * row_bytes).enumerate() {
+            let f = &f;
+            scope.spawn(move || f(index * band_rows, band));
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    // Imported per test, benches/compositing.rs builds this module without them
+
+    #[test]
+    fn mix_rounds_like_floating_point() {
+        use super::mix;
+
+        for alpha in 0..=255u32 {
+            for (src, dst) in [(0u8, 255u8), (255, 0), (200, 17), (3, 250)] {
+                let exact = (src as f64 * alpha as f64 + dst as f64 * (255 - alpha) as f64) / 255.0;
+                assert_eq!(mix(src, dst, alpha), exact.round() as u8, "alpha {}", alpha);
+            }
+        }
+    }
+
+    #[test]
+    fn rows_skip_transparent_and_copy_opaque_pixels() {
+        use super::blend_row;
+
+        let mut dst = vec![10, 20, 30, 255, 10, 20, 30, 255, 10, 20, 30, 255];
+        let src = [99, 99, 99, 0, 1, 2, 3, 255, 255, 255, 255, 128];
+        blend_row(&mut dst, &src);
+        assert_eq!(dst, vec![10, 20, 30, 255, 1, 2, 3, 255, 133, 138, 143, 255]);
+    }
+
+    #[test]
+    fn layers_are_clipped_to_the_region() {
+        use super::{Source, composite};
+
+        // A 4x4 buffer with an opaque 2x2 layer at (1, 1), redrawing only the top-left 2x2
+        let layer = [7u8, 7, 7, 255].repeat(4);
+        let sources = [Source {
+            bounds: (1, 1, 2, 2),
+            data: &layer,
+        }];
+        let mut buffer = vec![1u8; 4 * 4 * 4];
+        composite(&mut buffer, 4, (0, 0, 2, 2), &sources, 1);
+
+        let pixel = |x: usize, y: usize| &buffer[(y * 4 + x) * 4..(y * 4 + x) * 4 + 4];
+        assert_eq!(pixel(0, 0), [0, 0, 0, 0]);
+        assert_eq!(pixel(1, 1), [7, 7, 7, 255]);
+        // Outside the region nothing changes, even where the layer is
+        assert_eq!(pixel(2, 2), [1, 1, 1, 1]);
+        assert_eq!(pixel(2, 0), [1, 1, 1, 1]);
+    }
+
+    #[test]
+    fn threads_composite_the_same_as_one() {
+        use super::{Source, composite};
+
+        let layer: Vec<u8> = (0..20 * 10 * 4).map(|i| (i * 13 % 256) as u8).collect();
+        let sources = [Source {
+            bounds: (3, 2, 20, 10),
+            data: &layer,
+        }];
+
+        let mut single = vec![0u8; 32 * 16 * 4];
+        composite(&mut single, 32, (0, 0, 32, 16), &sources, 1);
+        let mut threaded = vec![0u8; 32 * 16 * 4];
+        composite(&mut threaded, 32, (0, 0, 32, 16), &sources, 3);
+        assert_eq!(single, threaded);
+    }
+}
@@ -1,107 +1,271 @@
-use crate::device::Device;
-use crate::r#virtual::layer::Layer;
+use crate::device::{Device, DeviceError};
+use crate::dither::{Dither, to_rgb565};
+use crate::r#virtual::blend::{Source, composite, for_each_band};
+use crate::r#virtual::layer::{Layer, Rect};
+use crate::r#virtual::tiles::{copy_in, copy_out};
+use crate::r#virtual::transform::Transform;
+use crate::r#virtual::transmitter::{DEFAULT_QUEUE_CAPACITY, Transmitter};
 use std::sync::{Arc, Mutex};
 
 pub struct Display {
-    width: u32,
+    width: u32, // Layout size, before the transform
     height: u32,
+    screen_width: u32,
+    screen_height: u32,
+    transform: Transform,
+    dither: Dither,
+    threads: usize,
     buffer: Vec<u8>, // RGBA Buffer: width * height * 4
     device_ref: Arc<Mutex<Device>>,
     layers: Vec<(u32, Arc<Mutex<dyn Layer + Send + Sync>>)>,
+    damage: Vec<Rect>,   // Regions to redraw that no layer will report
+    back: Vec<u8>,       // RGB565 screen being composited: screen_width * screen_height * 2
+    front: Arc<Vec<u8>>, // RGB565 screen last handed to the transmitter
+    transmitter: Transmitter,
 }
 
+/// Edge length of the tiles compared against the last transmitted frame.
+const DEFAULT_TILE_SIZE: u32 = 16;
+
 impl Display {
     pub fn new(device: Arc<Mutex<Device>>) -> Self {
         let (width, height) = {
             let dev = device.lock().unwrap();
             (dev.get_width(), dev.get_height())
         };
-        let buffer = vec![0; (width as u32 * height as u32 * 4) as usize];
+        let (width, height) = (width as u32, height as u32);
+        let buffer = vec![0; (width * height * 4) as usize];
 
         Display {
-            width: width as u32,
-            height: height as u32,
+            width,
+            height,
+            screen_width: width,
+            screen_height: height,
+            transform: Transform::default(),
+            dither: Dither::default(),
+            threads: 1,
             buffer,
+            transmitter: Transmitter::new(
+                device.clone(),
+                width,
+                height,
+                DEFAULT_TILE_SIZE,
+                DEFAULT_QUEUE_CAPACITY,
+            ),
             device_ref: device,
             layers: Vec::new(),
+            damage: Vec::new(),
+            back: vec![0; (width * height * 2) as usize],
+            front: Arc::new(vec![0; (width * height * 2) as usize]),
         }
     }
 
+    /// Sets the tile size used to skip regions that come out unchanged. Smaller tiles
+    /// send fewer pixels but more bitmap commands. Frames already queued are sent first,
+    /// and the next frame is sent in full.
+    pub fn set_tile_size(&mut self, tile_size: u32) {
+        self.transmitter = Transmitter::new(
+            self.device_ref.clone(),
+            self.screen_width,
+            self.screen_height,
+            tile_size,
+            DEFAULT_QUEUE_CAPACITY,
+        );
+    }
+
+    /// Rotates and mirrors everything drawn from now on. Layers are positioned in the
+    /// transformed layout, so after a 90 or 270 degree rotation the layout's width and
+    /// height are swapped. The whole screen is redrawn on the next `redraw`.
+    pub fn set_transform(&mut self, transform: Transform) {
+        let (width, height) = transform.layout_size(self.screen_width, self.screen_height);
+        self.transform = transform;
+        self.width = width;
+        self.height = height;
+        self.buffer = vec![0; (width * height * 4) as usize];
+        self.damage.push((0, 0, width, height));
+    }
+
+    /// Sets how the composited output is reduced to RGB565. Takes effect for regions
+    /// redrawn from now on.
+    pub fn set_dither(&mut self, dither: Dither) {
+        self.dither = dither;
+    }
+
+    /// Spreads compositing and RGB565 conversion of each region over `threads` threads.
+    /// Worth it on multi-core boards once regions are large; 1 keeps everything on the
+    /// calling thread.
+    pub fn set_threads(&mut self, threads: usize) {
+        self.threads = threads.max(1);
+    }
+
+    /// Size of the layout that layers are positioned in.
+    pub fn layout_size(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
     pub fn add_layer(&mut self, order: u32, layer: Arc<Mutex<dyn Layer + Send + Sync>>) {
+        self.damage.push(layer.lock().unwrap().bounding_box());
         self.layers.push((order, layer));
     }
 
-    fn draw_pixel(&mut self, x: u32, y: u32, rgba: [u8; 4]) {
-        if x >= self.width || y >= self.height {
-            return;
-        }
-        let idx = ((y * self.width + x) * 4) as usize;
-        let dst = &mut self.buffer[idx..idx + 4];
-        let src_a = rgba[3] as f32 / 255.0;
-        let inv_a = 1.0 - src_a;
-        dst[0] = (rgba[0] as f32 * src_a + dst[0] as f32 * inv_a) as u8;
-        dst[1] = (rgba[1] as f32 * src_a + dst[1] as f32 * inv_a) as u8;
-        dst[2] = (rgba[2] as f32 * src_a + dst[2] as f32 * inv_a) as u8;
-        // If you care about alpha channel in the framebuffer:
-        dst[3] = 255; // final image opaque or keep alpha if needed
+    /// Marks a region to be recomposited on the next `redraw`.
+    pub fn invalidate(&mut self, rect: Rect) {
+        self.damage.push(rect);
     }
 
+    /// Converts a region of the RGBA buffer to RGB565, splitting the rows between threads
+    /// unless Floyd-Steinberg dithering needs to see the region as a whole.
     fn as_rgb565_subregion(&self, x: u32, y: u32, w: u32, h: u32) -> Vec<u8> {
-        let mut output = Vec::with_capacity((w * h * 2) as usize);
-
-        for row in y..(y + h) {
-            let start = ((row * self.width + x) * 4) as usize;
-            let end = start + (w * 4) as usize;
-            let row_data = &self.buffer[start..end];
-
-            for pixel in row_data.chunks_exact(4) {
-                let r = pixel[0] as u16;
-                let g = pixel[1] as u16;
-                let b = pixel[2] as u16;
-                // Convert to RGB565
-                let rgb565 = ((r & 0xF8) << 8) | ((g & 0xFC) << 3) | (b >> 3);
-                output.push((rgb565 & 0xFF) as u8);
-                output.push((rgb565 >> 8) as u8);
+        let threads = match self.dither {
+            Dither::FloydSteinberg => 1,
+            _ => self.threads,
+        };
+        let mut output = vec![0; (w * h * 2) as usize];
+
+        for_each_band(&mut output, (w * 2) as usize, threads, |first, band| {
+            let rows = band.len() as u32 / (w * 2);
+            let top = y + first as u32;
+            let mut pixels = Vec::with_capacity((w * rows * 4) as usize);
+
+            for row in top..(top + rows) {
+                let start = ((row * self.width + x) * 4) as usize;
+                let end = start + (w * 4) as usize;
+                pixels.extend_from_slice(&self.buffer[start..end]);
             }
-        }
+
+            band.copy_from_slice(&to_rgb565(&pixels, 4, w, rows, (x, top), self.dither));
+        });
 
         output
     }
 
-    pub fn redraw_full(&mut self) {
-        // Clear entire display
-        self.buffer.fill(0);
-
+    /// Clears a region and blends every layer that overlaps it back on top.
+    fn composite_region(&mut self, rect: Rect) {
         self.layers.sort_by_key(|(order, _)| *order);
-        let layers = self.layers.clone();
-
-        for (order, layer) in layers {
-            let l = layer.lock().unwrap();
-            let layer_data = l.get_image_data();
-            let (lx, ly, lw, lh) = l.bounding_box();
-
-            // Blend each pixel of the layer onto the display buffer
-            for row in 0..lh {
-                for col in 0..lw {
-                    let idx = ((row * lw + col) * 4) as usize;
-                    let pixel = [
-                        layer_data[idx],
-                        layer_data[idx + 1],
-                        layer_data[idx + 2],
-                        layer_data[idx + 3],
-                    ];
-
-                    self.draw_pixel(lx + col, ly + row, pixel);
+
+        // Hold every layer still while its rows are read
+        let layers: Vec<_> = self
+            .layers
+            .iter()
+            .map(|(_, layer)| layer.lock().unwrap())
+            .collect();
+        let sources: Vec<Source> = layers
+            .iter()
+            .filter(|layer| rect_intersection(rect, layer.bounding_box()).is_some())
+            .map(|layer| Source {
+                bounds: layer.bounding_box(),
+                data: layer.get_image_data(),
+            })
+            .collect();
+
+        composite(&mut self.buffer, self.width, rect, &sources, self.threads);
+        drop(sources);
+        drop(layers);
+
+        let (x, y, w, h) = rect;
+        let data = self.as_rgb565_subregion(x, y, w, h);
+        let data = self.transform.map_pixels(&data, w, h, 2);
+        let screen_rect = self.to_screen(rect);
+        copy_in(&mut self.back, self.screen_width, screen_rect, &data);
+    }
+
+    /// Where a region of the layout ends up on the screen.
+    fn to_screen(&self, rect: Rect) -> Rect {
+        self.transform.map_rect(rect, self.width, self.height)
+    }
+
+    /// Swaps the composited frame to the front and hands it to the transmit thread, which
+    /// only ever reads it. The next frame is composited into the old front buffer, or into
+    /// a copy if the transmitter is still holding on to it.
+    fn present(&mut self, regions: Vec<Rect>, full: bool) -> Result<(), DeviceError> {
+        if regions.is_empty() {
+            return Ok(());
+        }
+
+        let front = Arc::new(std::mem::take(&mut self.back));
+        let previous = std::mem::replace(&mut self.front, front.clone());
+        self.back = match Arc::try_unwrap(previous) {
+            Ok(mut back) => {
+                // The old front is only missing what changed in this frame
+                for rect in &regions {
+                    copy_in(
+                        &mut back,
+                        self.screen_width,
+                        *rect,
+                        &copy_out(&front, self.screen_width, *rect),
+                    );
                 }
+                back
             }
+            Err(_) => front.as_ref().clone(),
+        };
+
+        self.transmitter.submit(front, regions, full)
+    }
+
+    /// Recomposites the whole screen and queues all of it to be sent.
+    pub fn redraw_full(&mut self) -> Result<(), DeviceError> {
+        // Everything is about to be redrawn, so pending damage no longer matters
+        self.take_damage();
+
+        self.composite_region((0, 0, self.width, self.height));
+        let screen = (0, 0, self.screen_width, self.screen_height);
+        self.present(vec![screen], true)
+    }
+
+    /// Recomposites the regions that changed since the last redraw and queues them. Only
+    /// the tiles in them that differ from what the device is already showing get sent.
+    pub fn redraw(&mut self) -> Result<(), DeviceError> {
+        let damage = self.take_damage();
+        for rect in &damage {
+            self.composite_region(*rect);
         }
 
-        let full_data = self.as_rgb565_subregion(0, 0, self.width, self.height);
-        self.device_ref
-            .lock()
-            .unwrap()
-            .display_picture(full_data, 0, 0, self.width as u16, self.height as u16)
-            .expect("Failed to update full display");
+        let damage = damage
+            .into_iter()
+            .map(|rect| self.to_screen(rect))
+            .collect();
+        self.present(damage, false)
+    }
+
+    /// Blocks until every queued frame has reached the device.
+    pub fn flush(&self) -> Result<(), DeviceError> {
+        self.transmitter.flush()
+    }
+
+    /// How many frames were merged into a later one because the device fell behind.
+    pub fn coalesced(&self) -> u64 {
+        self.transmitter.coalesced()
+    }
+
+    /// Collects damage from the display and every layer, clipped to the screen and with
+    /// overlapping regions merged.
+    fn take_damage(&mut self) -> Vec<Rect> {
+        let mut damage = std::mem::take(&mut self.damage);
+        for (_, layer) in &self.layers {
+            damage.extend(layer.lock().unwrap().take_damage());
+        }
+
+        let screen = (0, 0, self.width, self.height);
+        let mut merged: Vec<Rect> = Vec::new();
+
+        for rect in damage {
+            let Some(mut rect) = rect_intersection(rect, screen) else {
+                continue;
+            };
+
+            // Absorb anything the new region overlaps, repeating as it grows
+            while let Some(index) = merged.iter().position(|other| {
+                let (x1, y1, w1, h1) = rect;
+                let (x2, y2, w2, h2) = *other;
+                rects_intersect(x1, y1, w1, h1, x2, y2, w2, h2)
+            }) {
+                rect = rect_union(rect, merged.swap_remove(index));
+            }
+            merged.push(rect);
+        }
+
+        merged
     }
 }
 
@@ -110,3 +274,172 @@ fn rects_intersect(x1: u32, y1: u32, w1: u32, h1: u32, x2: u32, y2: u32, w2: u32
     let (r2x2, r2y2) = (x2 + w2, y2 + h2);
     !(r2x2 <= x1 || x2 >= r1x2 || r2y2 <= y1 || y2 >= r1y2)
 }
+
+fn rect_intersection(a: Rect, b: Rect) -> Option<Rect> {
+    let x = a.0.max(b.0);
+    let y = a.1.max(b.1);
+    let x2 = (a.0 + a.2).min(b.0 + b.2);
+    let y2 = (a.1 + a.3).min(b.1 + b.3);
+
+    if x2 <= x || y2 <= y {
+        return None;
+    }
+    Some((x, y, x2 - x, y2 - y))
+}
+
+fn rect_union(a: Rect, b: Rect) -> Rect {
+    let x = a.0.min(b.0);
+    let y = a.1.min(b.1);
+    let x2 = (a.0 + a.2).max(b.0 + b.2);
+    let y2 = (a.1 + a.3).max(b.1 + b.3);
+    (x, y, x2 - x, y2 - y)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packets::Packet;
+    use crate::protocol::rev_a::RevA;
+    use crate::transport::MemoryTransport;
+    use crate::r#virtual::image::Image;
+
+    /// A display over an 8x8 panel, with the first full frame already sent.
+    fn open(memory: &MemoryTransport) -> Display {
+        let device =
+            Device::with_protocol(Box::new(memory.clone()), Box::new(RevA::new()), 8, 8).unwrap();
+        let mut display = Display::new(Arc::new(Mutex::new(device)));
+        display.set_tile_size(1);
+        display.redraw_full().unwrap();
+        display.flush().unwrap();
+        memory.take_written();
+        display
+    }
+
+    /// The regions of every bitmap upload written so far, as (x, y, ex, ey).
+    fn uploads(memory: &MemoryTransport) -> Vec<(u16, u16, u16, u16)> {
+        let written = memory.take_written();
+        let mut uploads = Vec::new();
+        let mut offset = 0;
+        while let Ok((packet, len)) = Packet::decode(&written[offset..]) {
+            offset += len + packet.payload_len();
+            if let Packet::DisplayImage { x, y, ex, ey } = packet {
+                uploads.push((x, y, ex, ey));
+            }
+        }
+        uploads
+    }
+
+    fn solid(x: u32, y: u32, width: u32, height: u32, rgba: [u8; 4]) -> Image {
+        Image::new(x, y, width, height, rgba.repeat((width * height) as usize))
+    }
+
+    #[test]
+    fn damage_is_clipped_and_merged() {
+        let mut display = open(&MemoryTransport::new());
+
+        display.invalidate((0, 0, 2, 2));
+        display.invalidate((5, 0, 1, 1));
+        // Overlaps the first, then grows into the second
+        display.invalidate((1, 1, 2, 2));
+        display.invalidate((2, 0, 4, 1));
+        display.invalidate((6, 6, 4, 4));
+        // Touching edges don't overlap
+        display.invalidate((0, 6, 6, 2));
+
+        let mut damage = display.take_damage();
+        damage.sort();
+        assert_eq!(damage, vec![(0, 0, 6, 3), (0, 6, 6, 2), (6, 6, 2, 2)]);
+        assert!(display.take_damage().is_empty());
+    }
+
+    #[test]
+    fn layer_damage_is_collected() {
+        let mut display = open(&MemoryTransport::new());
+        let image = Arc::new(Mutex::new(solid(1, 1, 2, 2, [255; 4])));
+        display.add_layer(0, image.clone());
+        assert_eq!(display.take_damage(), vec![(1, 1, 2, 2)]);
+
+        image.lock().unwrap().set_position(4, 4);
+        let mut damage = display.take_damage();
+        damage.sort();
+        assert_eq!(damage, vec![(1, 1, 2, 2), (4, 4, 2, 2)]);
+    }
+
+    #[test]
+    fn redraw_sends_only_damaged_regions() {
+        let memory = MemoryTransport::new();
+        let mut display = open(&memory);
+        let background = Arc::new(Mutex::new(solid(0, 0, 8, 8, [0, 0, 255, 255])));
+        display.add_layer(0, background.clone());
+        display.redraw().unwrap();
+        display.flush().unwrap();
+        assert_eq!(uploads(&memory), vec![(0, 0, 7, 7)]);
+
+        // The whole layer changes, but only the region marked gets redrawn
+        *background.lock().unwrap() = solid(0, 0, 8, 8, [255, 0, 0, 255]);
+        display.invalidate((2, 3, 2, 2));
+        display.redraw().unwrap();
+        display.flush().unwrap();
+        assert_eq!(uploads(&memory), vec![(2, 3, 3, 4)]);
+
+        // Nothing damaged, nothing sent
+        display.redraw().unwrap();
+        display.flush().unwrap();
+        assert!(uploads(&memory).is_empty());
+    }
+
+    #[test]
+    fn moved_layers_redraw_where_they_were_and_are() {
+        let memory = MemoryTransport::new();
+        let mut display = open(&memory);
+        let image = Arc::new(Mutex::new(solid(0, 0, 1, 1, [255; 4])));
+        display.add_layer(0, image.clone());
+        display.redraw().unwrap();
+        display.flush().unwrap();
+        assert_eq!(uploads(&memory), vec![(0, 0, 0, 0)]);
+
+        image.lock().unwrap().set_position(5, 6);
+        display.redraw().unwrap();
+        display.flush().unwrap();
+        let mut sent = uploads(&memory);
+        sent.sort();
+        assert_eq!(sent, vec![(0, 0, 0, 0), (5, 6, 5, 6)]);
+    }
+
+    #[test]
+    fn presenting_while_frames_are_queued_keeps_both_buffers_whole() {
+        let memory = MemoryTransport::new();
+        let mut display = open(&memory);
+        let pixel = |buffer: &[u8], x: usize, y: usize| {
+            let index = (y * 8 + x) * 2;
+            [buffer[index], buffer[index + 1]]
+        };
+
+        // Hold the device so nothing presented gets sent yet
+        let device = display.device_ref.clone();
+        let held = device.lock().unwrap();
+
+        display.add_layer(0, Arc::new(Mutex::new(solid(0, 0, 1, 1, [255, 0, 0, 255]))));
+        display.redraw().unwrap();
+        assert_eq!(pixel(&display.front, 0, 0), [0x00, 0xF8]);
+        assert_eq!(display.back, *display.front);
+
+        // The first frame is still queued, so the next is composited into a copy of it
+        display.add_layer(1, Arc::new(Mutex::new(solid(5, 5, 1, 1, [0, 0, 255, 255]))));
+        display.redraw().unwrap();
+        assert!(Arc::strong_count(&display.front) > 1);
+        assert_eq!(pixel(&display.front, 0, 0), [0x00, 0xF8]);
+        assert_eq!(pixel(&display.front, 5, 5), [0x1F, 0x00]);
+        assert_eq!(display.back, *display.front);
+
+        drop(held);
+        display.flush().unwrap();
+        assert_eq!(uploads(&memory), vec![(0, 0, 0, 0), (5, 5, 5, 5)]);
+
+        // Once sent, the next frame still starts from everything on screen
+        display.invalidate((0, 0, 8, 8));
+        display.redraw().unwrap();
+        assert_eq!(pixel(&display.back, 0, 0), [0x00, 0xF8]);
+        assert_eq!(pixel(&display.back, 5, 5), [0x1F, 0x00]);
+    }
+}
@@ -1,4 +1,4 @@
-use crate::r#virtual::layer::Layer;
+use crate::r#virtual::layer::{Layer, LayerError, Rect};
 
 pub struct Image {
     x: u32,
@@ -6,6 +6,7 @@ pub struct Image {
     width: u32,
     height: u32,
     data: Vec<u8>, // RGBA data of the image
+    damage: Vec<Rect>,
 }
 
 impl Image {
@@ -16,16 +17,77 @@ impl Image {
             width,
             height,
             data,
+            damage: Vec::new(),
         }
     }
+
+    /// Replaces the image contents, which must be RGBA data of the same size.
+    pub fn set_image_data(&mut self, data: Vec<u8>) -> Result<(), LayerError> {
+        let expected = self.width as usize * self.height as usize * 4;
+        if data.len() != expected {
+            return Err(LayerError::DataLength {
+                expected,
+                actual: data.len(),
+            });
+        }
+
+        self.data = data;
+        self.damage.push(self.bounding_box());
+        Ok(())
+    }
+
+    /// Moves the image, redrawing both where it was and where it is now.
+    pub fn set_position(&mut self, x: u32, y: u32) {
+        self.damage.push(self.bounding_box());
+        self.x = x;
+        self.y = y;
+        self.damage.push(self.bounding_box());
+    }
 }
 
 impl Layer for Image {
-    fn bounding_box(&self) -> (u32, u32, u32, u32) {
+    fn bounding_box(&self) -> Rect {
         (self.x, self.y, self.width, self.height)
     }
 
     fn get_image_data(&self) -> &Vec<u8> {
         &self.data
     }
+
+    fn take_damage(&mut self) -> Vec<Rect> {
+        std::mem::take(&mut self.damage)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn image_data_must_match_size() {
+        let mut image = Image::new(1, 2, 3, 4, vec![0; 3 * 4 * 4]);
+        image.take_damage();
+
+        assert_eq!(
+            image.set_image_data(vec![0; 10]),
+            Err(LayerError::DataLength {
+                expected: 48,
+                actual: 10
+            })
+        );
+        assert!(image.take_damage().is_empty());
+
+        image.set_image_data(vec![1; 48]).unwrap();
+        assert_eq!(image.take_damage(), vec![(1, 2, 3, 4)]);
+        assert_eq!(image.get_image_data(), &vec![1; 48]);
+    }
+
+    #[test]
+    fn moving_damages_both_positions() {
+        let mut image = Image::new(1, 2, 3, 4, vec![0; 3 * 4 * 4]);
+
+        image.set_position(10, 20);
+        assert_eq!(image.bounding_box(), (10, 20, 3, 4));
+        assert_eq!(image.take_damage(), vec![(1, 2, 3, 4), (10, 20, 3, 4)]);
+    }
 }
@@ -1,6 +1,36 @@
 use crate::r#virtual::display::Display;
+use std::fmt;
+
+/// A screen region: x, y, width, height.
+pub type Rect = (u32, u32, u32, u32);
+
+/// Why a layer couldn't be changed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LayerError {
+    /// The new pixel data doesn't match the size of the layer.
+    DataLength { expected: usize, actual: usize },
+}
+
+impl fmt::Display for LayerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LayerError::DataLength { expected, actual } => write!(
+                f,
+                "expected {} bytes of layer data, got {}",
+                expected, actual
+            ),
+        }
+    }
+}
+
+impl std::error::Error for LayerError {}
 
 pub trait Layer {
-    fn bounding_box(&self) -> (u32, u32, u32, u32);
+    fn bounding_box(&self) -> Rect;
     fn get_image_data(&self) -> &Vec<u8>;
+
+    /// Returns the regions that need redrawing since the last call, and forgets them.
+    fn take_damage(&mut self) -> Vec<Rect> {
+        Vec::new()
+    }
 }
@@ -1,4 +1,8 @@
+pub mod blend;
 pub mod display;
 pub mod layer;
 pub mod image;
-pub mod text;
\ No newline at end of file
+pub mod text;
+pub mod tiles;
+pub mod transform;
+pub mod transmitter;
@@ -0,0 +1,218 @@
+use crate::r#virtual::layer::Rect;
+
+/// Splits a frame into square tiles to find what changed between two versions of it.
+pub struct TileGrid {
+    width: u32,
+    height: u32,
+    tile_size: u32,
+    bytes_per_pixel: u32,
+}
+
+impl TileGrid {
+    pub fn new(width: u32, height: u32, tile_size: u32, bytes_per_pixel: u32) -> Self {
+        TileGrid {
+            width,
+            height,
+            tile_size: tile_size.max(1),
+            bytes_per_pixel,
+        }
+    }
+
+    fn columns(&self) -> u32 {
+        self.width.div_ceil(self.tile_size)
+    }
+
+    fn rows(&self) -> u32 {
+        self.height.div_ceil(self.tile_size)
+    }
+
+    /// The tile at column `tx` and row `ty`, clipped to the frame.
+    fn tile_rect(&self, tx: u32, ty: u32) -> Rect {
+        let x = tx * self.tile_size;
+        let y = ty * self.tile_size;
+        (
+            x,
+            y,
+            self.tile_size.min(self.width - x),
+            self.tile_size.min(self.height - y),
+        )
+    }
+
+    fn tile_differs(&self, previous: &[u8], current: &[u8], tile: Rect) -> bool {
+        let (x, y, w, h) = tile;
+        let stride = (self.width * self.bytes_per_pixel) as usize;
+        let row_len = (w * self.bytes_per_pixel) as usize;
+
+        (y..y + h).any(|row| {
+            let start = row as usize * stride + (x * self.bytes_per_pixel) as usize;
+            previous[start..start + row_len] != current[start..start + row_len]
+        })
+    }
+
+    /// Compares every tile touching `regions` and returns rectangles covering the ones
+    /// that differ. Runs of changed tiles are merged across each row, and runs spanning
+    /// the same columns are merged down consecutive rows.
+    pub fn diff(&self, previous: &[u8], current: &[u8], regions: &[Rect]) -> Vec<Rect> {
+        let (columns, rows) = (self.columns(), self.rows());
+        let mut checked = vec![false; (columns * rows) as usize];
+        let mut changed = vec![false; (columns * rows) as usize];
+
+        for &(x, y, w, h) in regions {
+            if w == 0 || h == 0 || x >= self.width || y >= self.height {
+                continue;
+            }
+            let tx_end = (x + w).min(self.width).div_ceil(self.tile_size);
+            let ty_end = (y + h).min(self.height).div_ceil(self.tile_size);
+
+            for ty in y / self.tile_size..ty_end {
+                for tx in x / self.tile_size..tx_end {
+                    let index = (ty * columns + tx) as usize;
+                    if !checked[index] {
+                        checked[index] = true;
+                        changed[index] =
+                            self.tile_differs(previous, current, self.tile_rect(tx, ty));
+                    }
+                }
+            }
+        }
+
+        // Each open span is (first column, end column, first row), still growing downwards
+        let mut open: Vec<(u32, u32, u32)> = Vec::new();
+        let mut spans: Vec<(u32, u32, u32, u32)> = Vec::new();
+
+        for ty in 0..=rows {
+            let mut runs = Vec::new();
+            if ty < rows {
+                let mut tx = 0;
+                while tx < columns {
+                    if changed[(ty * columns + tx) as usize] {
+                        let start = tx;
+                        while tx < columns && changed[(ty * columns + tx) as usize] {
+                            tx += 1;
+                        }
+                        runs.push((start, tx));
+                    } else {
+                        tx += 1;
+                    }
+                }
+            }
+
+            let mut still_open = Vec::new();
+            for (start, end, first_row) in open {
+                if let Some(position) = runs.iter().position(|run| *run == (start, end)) {
+                    runs.remove(position);
+                    still_open.push((start, end, first_row));
+                } else {
+                    spans.push((start, end, first_row, ty));
+                }
+            }
+            still_open.extend(runs.into_iter().map(|(start, end)| (start, end, ty)));
+            open = still_open;
+        }
+
+        spans
+            .into_iter()
+            .map(|(tx_start, tx_end, ty_start, ty_end)| {
+                let x = tx_start * self.tile_size;
+                let y = ty_start * self.tile_size;
+                let x_end = (tx_end * self.tile_size).min(self.width);
+                let y_end = (ty_end * self.tile_size).min(self.height);
+                (x, y, x_end - x, y_end - y)
+            })
+            .collect()
+    }
+}
+
+/// Copies a tightly packed RGB565 region into a full-width frame.
+pub fn copy_in(frame: &mut [u8], frame_width: u32, rect: Rect, data: &[u8]) {
+    let (x, y, w, h) = rect;
+    let row_len = (w * 2) as usize;
+    for row in 0..h {
+        let dst = (((y + row) * frame_width + x) * 2) as usize;
+        let src = row as usize * row_len;
+        frame[dst..dst + row_len].copy_from_slice(&data[src..src + row_len]);
+    }
+}
+
+/// Copies a region out of a full-width RGB565 frame into a tightly packed buffer.
+pub fn copy_out(frame: &[u8], frame_width: u32, rect: Rect) -> Vec<u8> {
+    let (x, y, w, h) = rect;
+    let row_len = (w * 2) as usize;
+    let mut data = Vec::with_capacity(row_len * h as usize);
+    for row in 0..h {
+        let src = (((y + row) * frame_width + x) * 2) as usize;
+        data.extend_from_slice(&frame[src..src + row_len]);
+    }
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn set_pixel(frame: &mut [u8], width: u32, x: u32, y: u32) {
+        frame[((y * width + x) * 2) as usize] = 0xFF;
+    }
+
+    #[test]
+    fn unchanged_frames_have_no_diff() {
+        let grid = TileGrid::new(32, 32, 8, 2);
+        let frame = vec![0; 32 * 32 * 2];
+        assert!(grid.diff(&frame, &frame, &[(0, 0, 32, 32)]).is_empty());
+    }
+
+    #[test]
+    fn changed_tiles_are_merged_into_rectangles() {
+        let grid = TileGrid::new(32, 32, 8, 2);
+        let previous = vec![0; 32 * 32 * 2];
+        let mut current = previous.clone();
+
+        // Two tiles side by side in two consecutive rows, plus one on its own
+        for (x, y) in [(1, 1), (9, 2), (3, 12), (15, 15), (30, 30)] {
+            set_pixel(&mut current, 32, x, y);
+        }
+
+        let mut diff = grid.diff(&previous, &current, &[(0, 0, 32, 32)]);
+        diff.sort();
+        assert_eq!(diff, vec![(0, 0, 16, 16), (24, 24, 8, 8)]);
+    }
+
+    #[test]
+    fn only_tiles_touching_the_regions_are_compared() {
+        let grid = TileGrid::new(32, 32, 8, 2);
+        let previous = vec![0; 32 * 32 * 2];
+        let mut current = previous.clone();
+        set_pixel(&mut current, 32, 1, 1);
+        set_pixel(&mut current, 32, 20, 20);
+
+        assert_eq!(
+            grid.diff(&previous, &current, &[(18, 18, 2, 2)]),
+            vec![(16, 16, 8, 8)]
+        );
+    }
+
+    #[test]
+    fn edge_tiles_are_clipped_to_the_frame() {
+        let grid = TileGrid::new(20, 10, 8, 2);
+        let previous = vec![0; 20 * 10 * 2];
+        let mut current = previous.clone();
+        set_pixel(&mut current, 20, 19, 9);
+
+        assert_eq!(
+            grid.diff(&previous, &current, &[(0, 0, 20, 10)]),
+            vec![(16, 8, 4, 2)]
+        );
+    }
+
+    #[test]
+    fn copy_out_and_in_round_trip() {
+        let frame: Vec<u8> = (0..6 * 4 * 2).map(|i| i as u8).collect();
+        let rect = (1, 1, 3, 2);
+        let data = copy_out(&frame, 6, rect);
+        assert_eq!(data.len(), 3 * 2 * 2);
+
+        let mut copy = vec![0; frame.len()];
+        copy_in(&mut copy, 6, rect, &data);
+        assert_eq!(copy_out(&copy, 6, rect), data);
+    }
+}
@@ -0,0 +1,198 @@
+use crate::r#virtual::layer::Rect;
+
+/// Clockwise rotation applied to the layout before it reaches the panel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Rotation {
+    #[default]
+    Rotate0,
+    Rotate90,
+    Rotate180,
+    Rotate270,
+}
+
+impl Rotation {
+    pub fn from_degrees(degrees: u16) -> Option<Self> {
+        match degrees {
+            0 => Some(Rotation::Rotate0),
+            90 => Some(Rotation::Rotate90),
+            180 => Some(Rotation::Rotate180),
+            270 => Some(Rotation::Rotate270),
+            _ => None,
+        }
+    }
+
+    fn swaps_axes(&self) -> bool {
+        matches!(self, Rotation::Rotate90 | Rotation::Rotate270)
+    }
+}
+
+/// How a layout maps onto the panel's screen. The layout is mirrored first, then rotated.
+///
+/// Mirroring is for screens seen in a mirror or through two-way glass: `mirror_x` flips
+/// left and right, `mirror_y` flips top and bottom.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Transform {
+    pub rotation: Rotation,
+    pub mirror_x: bool,
+    pub mirror_y: bool,
+}
+
+impl Transform {
+    pub fn rotate(rotation: Rotation) -> Self {
+        Transform {
+            rotation,
+            ..Default::default()
+        }
+    }
+
+    pub fn is_identity(&self) -> bool {
+        *self == Transform::default()
+    }
+
+    /// The layout size that fills a screen of `width` x `height`.
+    pub fn layout_size(&self, width: u32, height: u32) -> (u32, u32) {
+        if self.rotation.swaps_axes() {
+            (height, width)
+        } else {
+            (width, height)
+        }
+    }
+
+    /// Maps a rectangle in a `layout_width` x `layout_height` layout onto the screen.
+    pub fn map_rect(&self, rect: Rect, layout_width: u32, layout_height: u32) -> Rect {
+        let (mut x, mut y, w, h) = rect;
+        if self.mirror_x {
+            x = layout_width - x - w;
+        }
+        if self.mirror_y {
+            y = layout_height - y - h;
+        }
+
+        match self.rotation {
+            Rotation::Rotate0 => (x, y, w, h),
+            Rotation::Rotate90 => (layout_height - y - h, x, h, w),
+            Rotation::Rotate180 => (layout_width - x - w, layout_height - y - h, w, h),
+            Rotation::Rotate270 => (y, layout_width - x - w, h, w),
+        }
+    }
+
+    /// Rearranges the pixels of a `width` x `height` region to match `map_rect`.
+    pub fn map_pixels(
+        &self,
+        data: &[u8],
+        width: u32,
+        height: u32,
+        bytes_per_pixel: u32,
+    ) -> Vec<u8> {
+        if self.is_identity() {
+            return data.to_vec();
+        }
+
+        let (w, h) = (width as usize, height as usize);
+        let bpp = bytes_per_pixel as usize;
+        let out_width = if self.rotation.swaps_axes() { h } else { w };
+        let mut output = vec![0; data.len()];
+
+        for sy in 0..h {
+            for sx in 0..w {
+                let mx = if self.mirror_x { w - 1 - sx } else { sx };
+                let my = if self.mirror_y { h - 1 - sy } else { sy };
+                let (dx, dy) = match self.rotation {
+                    Rotation::Rotate0 => (mx, my),
+                    Rotation::Rotate90 => (h - 1 - my, mx),
+                    Rotation::Rotate180 => (w - 1 - mx, h - 1 - my),
+                    Rotation::Rotate270 => (my, w - 1 - mx),
+                };
+
+                let src = (sy * w + sx) * bpp;
+                let dst = (dy * out_width + dx) * bpp;
+                output[dst..dst + bpp].copy_from_slice(&data[src..src + bpp]);
+            }
+        }
+
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROTATIONS: [Rotation; 4] = [
+        Rotation::Rotate0,
+        Rotation::Rotate90,
+        Rotation::Rotate180,
+        Rotation::Rotate270,
+    ];
+
+    /// A 3x2 layout where every pixel is its own index.
+    fn numbered() -> Vec<u8> {
+        (0..6).collect()
+    }
+
+    #[test]
+    fn rotations_are_clockwise() {
+        let layout = numbered();
+        // 0 1 2
+        // 3 4 5
+        let rotated = Transform::rotate(Rotation::Rotate90).map_pixels(&layout, 3, 2, 1);
+        assert_eq!(rotated, vec![3, 0, 4, 1, 5, 2]);
+        let rotated = Transform::rotate(Rotation::Rotate180).map_pixels(&layout, 3, 2, 1);
+        assert_eq!(rotated, vec![5, 4, 3, 2, 1, 0]);
+        let rotated = Transform::rotate(Rotation::Rotate270).map_pixels(&layout, 3, 2, 1);
+        assert_eq!(rotated, vec![2, 5, 1, 4, 0, 3]);
+    }
+
+    #[test]
+    fn mirroring_happens_before_rotating() {
+        let transform = Transform {
+            rotation: Rotation::Rotate90,
+            mirror_x: true,
+            mirror_y: false,
+        };
+        // Mirrored: 2 1 0 / 5 4 3, then rotated
+        assert_eq!(
+            transform.map_pixels(&numbered(), 3, 2, 1),
+            vec![5, 2, 4, 1, 3, 0]
+        );
+    }
+
+    #[test]
+    fn mapped_rects_hold_the_mapped_pixels() {
+        // A 4x3 layout with a 2x1 region at (1, 1) marked
+        let (width, height) = (4, 3);
+        let mut layout = vec![0u8; 12];
+        layout[5] = 1;
+        layout[6] = 1;
+
+        for rotation in ROTATIONS {
+            for (mirror_x, mirror_y) in [(false, false), (true, false), (false, true)] {
+                let transform = Transform {
+                    rotation,
+                    mirror_x,
+                    mirror_y,
+                };
+                let screen = transform.map_pixels(&layout, width, height, 1);
+                let (screen_width, _) = transform.layout_size(width, height);
+                let (x, y, w, h) = transform.map_rect((1, 1, 2, 1), width, height);
+
+                let marked: Vec<usize> = (0..screen.len()).filter(|&i| screen[i] == 1).collect();
+                let expected: Vec<usize> = (y..y + h)
+                    .flat_map(|row| (x..x + w).map(move |col| (row * screen_width + col) as usize))
+                    .collect();
+                assert_eq!(marked, expected, "{:?}", transform);
+            }
+        }
+    }
+
+    #[test]
+    fn quarter_turns_swap_the_layout_size() {
+        assert_eq!(Transform::default().layout_size(480, 320), (480, 320));
+        assert_eq!(
+            Transform::rotate(Rotation::Rotate270).layout_size(480, 320),
+            (320, 480)
+        );
+        assert_eq!(Rotation::from_degrees(90), Some(Rotation::Rotate90));
+        assert_eq!(Rotation::from_degrees(45), None);
+    }
+}
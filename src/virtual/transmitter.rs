@@ -0,0 +1,391 @@
+use crate::device::{Device, DeviceError};
+use crate::r#virtual::layer::Rect;
+use crate::r#virtual::tiles::{TileGrid, copy_in, copy_out};
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// A composited RGB565 frame and the regions of it that may have changed.
+struct FrameUpdate {
+    frame: Arc<Vec<u8>>,
+    regions: Vec<Rect>,
+    // Send the regions as they are instead of diffing them against the last frame
+    full: bool,
+}
+
+struct State {
+    queue: VecDeque<FrameUpdate>,
+    capacity: usize,
+    busy: bool,
+    closed: bool,
+    error: Option<DeviceError>,
+    coalesced: u64,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+/// Streams frames to a device on a worker thread so rendering never waits on the link.
+///
+/// At most `capacity` frames are queued. When the device falls behind, a new frame is
+/// merged into the newest queued one: only the latest pixels are kept, and the regions of
+/// both are sent.
+pub struct Transmitter {
+    shared: Arc<Shared>,
+    worker: Option<JoinHandle<()>>,
+}
+
+/// Frames queued behind the one being sent before new ones get merged.
+pub const DEFAULT_QUEUE_CAPACITY: usize = 2;
+
+/// How long the link may sit idle before the panel is checked for a hang.
+const WATCHDOG_INTERVAL: Duration = Duration::from_secs(10);
+
+impl Transmitter {
+    pub fn new(
+        device: Arc<Mutex<Device>>,
+        width: u32,
+        height: u32,
+        tile_size: u32,
+        capacity: usize,
+    ) -> Self {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                queue: VecDeque::new(),
+                capacity: capacity.max(1),
+                busy: false,
+                closed: false,
+                error: None,
+                coalesced: 0,
+            }),
+            changed: Condvar::new(),
+        });
+
+        let worker = {
+            let shared = shared.clone();
+            let tiles = TileGrid::new(width, height, tile_size, 2);
+            thread::spawn(move || transmit(shared, device, width, height, tiles))
+        };
+
+        Transmitter {
+            shared,
+            worker: Some(worker),
+        }
+    }
+
+    /// Queues a frame, merging it into the newest queued one if the queue is full.
+    /// Returns the error from an earlier frame that failed to send, if there was one.
+    pub fn submit(
+        &self,
+        frame: Arc<Vec<u8>>,
+        regions: Vec<Rect>,
+        full: bool,
+    ) -> Result<(), DeviceError> {
+        let mut state = self.shared.state.lock().unwrap();
+        if let Some(error) = state.error.take() {
+            return Err(error);
+        }
+
+        if state.queue.len() >= state.capacity
+            && let Some(newest) = state.queue.back_mut()
+        {
+            newest.frame = frame;
+            newest.regions.extend(regions);
+            newest.full |= full;
+            state.coalesced += 1;
+        } else {
+            state.queue.push_back(FrameUpdate {
+                frame,
+                regions,
+                full,
+            });
+        }
+
+        self.shared.changed.notify_all();
+        Ok(())
+    }
+
+    /// Blocks until every queued frame has been sent.
+    pub fn flush(&self) -> Result<(), DeviceError> {
+        let mut state = self.shared.state.lock().unwrap();
+        while !state.queue.is_empty() || state.busy {
+            state = self.shared.changed.wait(state).unwrap();
+        }
+
+        match state.error.take() {
+            Some(error) => Err(error),
+            None => Ok(()),
+        }
+    }
+
+    /// How many frames were merged into another because the device fell behind.
+    pub fn coalesced(&self) -> u64 {
+        self.shared.state.lock().unwrap().coalesced
+    }
+}
+
+impl Drop for Transmitter {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().closed = true;
+        self.shared.changed.notify_all();
+
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+/// Runs a command, then lets any paced upload or recovery it started finish with the
+/// device unlocked. Also returns whether the link had to be brought back.
+fn run_settled<F>(
+    shared: &Shared,
+    device: &Mutex<Device>,
+    command: F,
+) -> (Result<(), DeviceError>, bool)
+where
+    F: FnOnce(&mut Device) -> Result<(), DeviceError>,
+{
+    let result = command(&mut device.lock().unwrap());
+    match result {
+        Ok(()) => (settle(shared, device), false),
+        Err(DeviceError::Recovering { .. }) => (settle(shared, device), true),
+        Err(err) => (Err(err), false),
+    }
+}
+
+/// Polls the device until it is no longer waiting on anything, like `device::settle`, but
+/// waits on the queue in between. Once the transmitter is closed it stops waiting for the
+/// link to come back, so dropping it never sits out a reconnect backoff.
+fn settle(shared: &Shared, device: &Mutex<Device>) -> Result<(), DeviceError> {
+    loop {
+        let (next, recovering) = {
+            let mut device = device.lock().unwrap();
+            (device.poll()?, device.is_recovering())
+        };
+        let Some(due) = next else {
+            return Ok(());
+        };
+
+        let state = shared.state.lock().unwrap();
+        if state.closed && recovering {
+            return Ok(());
+        }
+        let timeout = due.saturating_duration_since(Instant::now());
+        let _ = shared.changed.wait_timeout(state, timeout).unwrap();
+    }
+}
+
+fn transmit(
+    shared: Arc<Shared>,
+    device: Arc<Mutex<Device>>,
+    width: u32,
+    height: u32,
+    tiles: TileGrid,
+) {
+    let mut last_sent: Vec<u8> = Vec::new();
+    let mut last_sent_valid = false;
+
+    loop {
+        let update = {
+            let mut state = shared.state.lock().unwrap();
+            loop {
+                if let Some(update) = state.queue.pop_front() {
+                    state.busy = true;
+                    break Some(update);
+                }
+                if state.closed {
+                    return;
+                }
+                let (next, wait) = shared
+                    .changed
+                    .wait_timeout(state, WATCHDOG_INTERVAL)
+                    .unwrap();
+                state = next;
+                if wait.timed_out() && state.queue.is_empty() && !state.closed {
+                    state.busy = true;
+                    break None;
+                }
+            }
+        };
+
+        let Some(update) = update else {
+            // Nothing sent for a while, which is when a hung panel goes unnoticed
+            let (result, recovered) = run_settled(&shared, &device, Device::check_alive);
+            if recovered || result.is_err() {
+                last_sent_valid = false;
+            }
+
+            let mut state = shared.state.lock().unwrap();
+            state.busy = false;
+            if let Err(error) = result {
+                state.error = Some(error);
+            }
+            shared.changed.notify_all();
+            continue;
+        };
+
+        if last_sent.len() != update.frame.len() {
+            last_sent = vec![0; update.frame.len()];
+            last_sent_valid = false;
+        }
+
+        let changed = if !last_sent_valid {
+            // Nothing is known about what the screen shows, so resync all of it
+            vec![(0, 0, width, height)]
+        } else if update.full {
+            update.regions
+        } else {
+            tiles.diff(&last_sent, &update.frame, &update.regions)
+        };
+
+        let mut result = Ok(());
+        let mut recovered = false;
+        for rect in changed {
+            let (x, y, w, h) = rect;
+            let data = copy_out(&update.frame, width, rect);
+            copy_in(&mut last_sent, width, rect, &data);
+
+            let sent = run_settled(&shared, &device, |device| {
+                device.display_picture(data, x as u16, y as u16, w as u16, h as u16)
+            });
+            result = sent.0;
+            recovered |= sent.1;
+            if result.is_err() {
+                break;
+            }
+        }
+
+        // No telling what made it to the screen after a failure
+        last_sent_valid = result.is_ok() && !recovered;
+
+        let mut state = shared.state.lock().unwrap();
+        state.busy = false;
+        if let Err(error) = result {
+            state.error = Some(error);
+        }
+        shared.changed.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::{DeviceConfig, PortSelector};
+    use crate::packets::Packet;
+    use crate::protocol::Revision;
+    use crate::protocol::rev_a::RevA;
+    use crate::transport::MemoryTransport;
+
+    /// The regions of every bitmap upload written so far, as (x, y, ex, ey).
+    fn uploads(memory: &MemoryTransport) -> Vec<(u16, u16, u16, u16)> {
+        let written = memory.take_written();
+        let mut uploads = Vec::new();
+        let mut offset = 0;
+        while let Ok((packet, len)) = Packet::decode(&written[offset..]) {
+            offset += len + packet.payload_len();
+            if let Packet::DisplayImage { x, y, ex, ey } = packet {
+                uploads.push((x, y, ex, ey));
+            }
+        }
+        uploads
+    }
+
+    #[test]
+    fn first_update_resyncs_the_whole_frame() {
+        let memory = MemoryTransport::new();
+        let device =
+            Device::with_protocol(Box::new(memory.clone()), Box::new(RevA::new()), 8, 8).unwrap();
+        let transmitter = Transmitter::new(Arc::new(Mutex::new(device)), 8, 8, 4, 2);
+        memory.take_written();
+
+        let mut frame = vec![0; 8 * 8 * 2];
+        frame[0] = 1;
+        transmitter
+            .submit(Arc::new(frame.clone()), vec![(0, 0, 1, 1)], false)
+            .unwrap();
+        transmitter.flush().unwrap();
+        assert_eq!(uploads(&memory), vec![(0, 0, 7, 7)]);
+
+        // Unchanged tiles are skipped from then on
+        frame[(5 * 8 + 5) * 2] = 1;
+        transmitter
+            .submit(Arc::new(frame), vec![(0, 0, 8, 8)], false)
+            .unwrap();
+        transmitter.flush().unwrap();
+        assert_eq!(uploads(&memory), vec![(4, 4, 7, 7)]);
+    }
+
+    #[test]
+    fn frames_queued_while_busy_are_merged() {
+        let memory = MemoryTransport::new();
+        let device =
+            Device::with_protocol(Box::new(memory.clone()), Box::new(RevA::new()), 8, 8).unwrap();
+        let device = Arc::new(Mutex::new(device));
+        let transmitter = Transmitter::new(device.clone(), 8, 8, 1, 1);
+
+        let mut frame = vec![0; 8 * 8 * 2];
+        transmitter
+            .submit(Arc::new(frame.clone()), vec![(0, 0, 8, 8)], false)
+            .unwrap();
+        transmitter.flush().unwrap();
+        memory.take_written();
+
+        // Hold the device so the first frame gets stuck on its way out
+        let held = device.lock().unwrap();
+        frame[0] = 1;
+        transmitter
+            .submit(Arc::new(frame.clone()), vec![(0, 0, 1, 1)], false)
+            .unwrap();
+        while !transmitter.shared.state.lock().unwrap().busy {
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        // One frame fills the queue, the rest are merged into it
+        for (x, y, w, h) in [(2, 2, 1, 1), (3, 2, 1, 1), (2, 3, 2, 1)] {
+            for px in x..x + w {
+                frame[(y * 8 + px) as usize * 2] = 2;
+            }
+            transmitter
+                .submit(Arc::new(frame.clone()), vec![(x, y, w, h)], false)
+                .unwrap();
+        }
+        assert_eq!(transmitter.coalesced(), 2);
+
+        drop(held);
+        transmitter.flush().unwrap();
+        let written = memory.written();
+        assert_eq!(uploads(&memory), vec![(0, 0, 0, 0), (2, 2, 3, 3)]);
+        // The merged upload carries the newest pixels
+        assert_eq!(written[written.len() - 8..], [2, 0, 2, 0, 2, 0, 2, 0]);
+    }
+
+    #[test]
+    fn closing_does_not_wait_for_the_panel_to_come_back() {
+        let path = std::env::temp_dir().join(format!("gibmon-transmitter-{}", std::process::id()));
+        let mut device = Device::new(DeviceConfig {
+            port: PortSelector::File(path.clone()),
+            revision: Some(Revision::A),
+            width: 8,
+            height: 8,
+            ..Default::default()
+        })
+        .unwrap();
+        // The panel is away for seconds after a reset
+        device.reset().unwrap();
+        let transmitter = Transmitter::new(Arc::new(Mutex::new(device)), 8, 8, 4, 2);
+
+        transmitter
+            .submit(Arc::new(vec![0; 8 * 8 * 2]), vec![(0, 0, 8, 8)], false)
+            .unwrap();
+        thread::sleep(Duration::from_millis(50));
+
+        let closing = Instant::now();
+        drop(transmitter);
+        assert!(closing.elapsed() < Duration::from_secs(1));
+
+        std::fs::remove_file(path).unwrap();
+    }
+}